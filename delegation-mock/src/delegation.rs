@@ -96,28 +96,66 @@ pub trait DelegationMock<ContractReader> {
         }
     }
 
-    #[endpoint(getClaimableRewards)]
-    fn get_claimable_rewards(&self) -> BigUint {
+    #[endpoint(claimRewards)]
+    fn claim_rewards(&self) {
         let caller = self.blockchain().get_caller();
+        let rewards = self.get_claimable_rewards(caller.clone());
+        self.address_last_claim_epoch(&caller)
+            .set(self.blockchain().get_block_epoch());
+        if rewards == 0 {
+            return
+        }
+
+        self.egld_token_supply()
+            .update(|value| *value -= &rewards);
+        self.send_raw().async_call_raw(
+            &caller,
+            &rewards,
+            &ManagedBuffer::new(),
+            &ManagedArgBuffer::new(),
+        );
+    }
+
+    #[endpoint(getClaimableRewards)]
+    fn get_claimable_rewards(&self, address: ManagedAddress) -> BigUint {
         let current_epoch = self.blockchain().get_block_epoch();
-        let last_claim_epoch = self.address_last_claim_epoch(&caller).get();
-        let total_deposit = self.address_deposit(&caller).get();
+        let last_claim_epoch = self.address_last_claim_epoch(&address).get();
+        let total_deposit = self.address_deposit(&address).get();
 
         if current_epoch > last_claim_epoch {
-            let rewards = (total_deposit * APY / MAX_PERCENTAGE)
-                * (current_epoch - last_claim_epoch) / EPOCHS_IN_YEAR;
-
-            rewards
+            (total_deposit * APY / MAX_PERCENTAGE)
+                * (current_epoch - last_claim_epoch) / EPOCHS_IN_YEAR
         } else {
             BigUint::zero()
         }
     }
 
     #[endpoint(getUserActiveStake)]
-    fn get_user_active_stake(&self) -> BigUint {
-        let caller = self.blockchain().get_caller();
+    fn get_user_active_stake(&self, address: ManagedAddress) -> BigUint {
+        self.address_deposit(&address).get()
+    }
 
-        self.address_deposit(&caller).get()
+    #[endpoint(getDelegatorFundsData)]
+    fn get_delegator_funds_data(
+        &self,
+        address: ManagedAddress,
+    ) -> MultiValue4<BigUint, BigUint, BigUint, BigUint> {
+        let current_epoch = self.blockchain().get_block_epoch();
+        let undelegate_epoch = self.address_undelegate_epoch(&address).get();
+        let undelegate_amount = self.address_undelegate_amount(&address).get();
+        let unbondable = if undelegate_epoch > 0 && current_epoch >= undelegate_epoch {
+            undelegate_amount.clone()
+        } else {
+            BigUint::zero()
+        };
+
+        (
+            self.address_deposit(&address).get(),
+            self.get_claimable_rewards(address),
+            undelegate_amount,
+            unbondable,
+        )
+            .into()
     }
 
     #[storage_mapper("egldTokenSupply")]
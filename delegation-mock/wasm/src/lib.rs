@@ -5,9 +5,9 @@
 ////////////////////////////////////////////////////
 
 // Init:                                 1
-// Endpoints:                            7
+// Endpoints:                            9
 // Async Callback (empty):               1
-// Total number of exported functions:   9
+// Total number of exported functions:  11
 
 #![no_std]
 #![feature(lang_items)]
@@ -23,8 +23,10 @@ multiversx_sc_wasm_adapter::endpoints! {
         unDelegate
         withdraw
         reDelegateRewards
+        claimRewards
         getClaimableRewards
         getUserActiveStake
+        getDelegatorFundsData
     )
 }
 
@@ -1,19 +1,22 @@
 multiversx_sc::imports!();
 multiversx_sc::derive_imports!();
 
-use crate::{consts::*, errors::*};
+use crate::{consts::*, errors::*, events};
 
-#[derive(TypeAbi, TopEncode, TopDecode, PartialEq, Eq, Copy, Clone, Debug)]
+#[derive(TypeAbi, TopEncode, TopDecode, NestedEncode, NestedDecode, PartialEq, Eq, Copy, Clone, Debug)]
 pub enum State {
     Inactive,
     Active,
+    ExitOnly,
+    Deprecated,
 }
 
 #[derive(TypeAbi, TopEncode, TopDecode, PartialEq, Eq, Copy, Clone, Debug)]
 pub enum UndelegationType {
-    UserList,
     TotalUsersList,
     ReservesList,
+    ProviderList,
+    MigrationList,
 }
 
 #[derive(ManagedVecItem, TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
@@ -22,9 +25,116 @@ pub struct Undelegation<M: ManagedTypeApi> {
     pub unbond_epoch: u64,
 }
 
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct UndelegationLoss<M: ManagedTypeApi> {
+    pub cutoff_epoch: u64,
+    pub loss_index: BigUint<M>,
+}
+
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct ReserveFeesBatch<M: ManagedTypeApi> {
+    pub amount: BigUint<M>,
+    pub end_epoch: u64,
+}
+
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct ProviderConfig<M: ManagedTypeApi> {
+    pub weight: u64,
+    pub total_staked: BigUint<M>,
+}
+
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct UndelegationOrder<M: ManagedTypeApi> {
+    pub owner: ManagedAddress<M>,
+    pub ls_amount: BigUint<M>,
+    pub max_fee: u64,
+}
+
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct ReserveExit<M: ManagedTypeApi> {
+    pub id: u64,
+    pub owner: ManagedAddress<M>,
+    pub reserve_points: BigUint<M>,
+    pub epoch: u64,
+}
+
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct LockedReserve<M: ManagedTypeApi> {
+    pub reserve_points: BigUint<M>,
+    pub lock_epochs: u64,
+    pub multiplier: u64,
+    pub unlock_epoch: u64,
+    pub boost_index: BigUint<M>,
+    pub boost_rewards: BigUint<M>,
+}
+
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct PendingOperations<M: ManagedTypeApi> {
+    pub can_delegate_all: bool,
+    pub egld_to_delegate: BigUint<M>,
+    pub can_undelegate_all: bool,
+    pub egld_to_undelegate: BigUint<M>,
+    pub can_compound: bool,
+    pub can_withdraw_all: bool,
+    pub matured_users_undelegations: BigUint<M>,
+    pub matured_reserves_undelegations: BigUint<M>,
+    pub can_compute_withdrawn: bool,
+    pub withdrawn_egld: BigUint<M>,
+}
+
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct ContractInfo<M: ManagedTypeApi> {
+    pub state: State,
+    pub token_price: BigUint<M>,
+    pub total_egld_staked: BigUint<M>,
+    pub liquid_token_supply: BigUint<M>,
+    pub egld_reserve: BigUint<M>,
+    pub available_egld_reserve: BigUint<M>,
+    pub reserve_points: BigUint<M>,
+    pub undelegate_now_fee: u64,
+    pub undelegate_now_max_fee: u64,
+    pub protocol_fee: u64,
+    pub unbond_period: u64,
+    pub egld_to_delegate: BigUint<M>,
+    pub egld_to_undelegate: BigUint<M>,
+    pub total_withdrawn_egld: BigUint<M>,
+    pub user_withdrawn_egld: BigUint<M>,
+    pub total_losses: BigUint<M>,
+    pub providers_count: usize,
+}
+
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct UserPosition<M: ManagedTypeApi> {
+    pub reserve_points: BigUint<M>,
+    pub reserve_egld: BigUint<M>,
+    pub locked_reserve_points: BigUint<M>,
+    pub locked_reserve_egld: BigUint<M>,
+    pub unlock_epoch: u64,
+    pub boost_rewards: BigUint<M>,
+    pub queued_reserve_points: BigUint<M>,
+    pub queued_reserve_egld: BigUint<M>,
+    pub withdrawable_undelegations: ManagedVec<M, Undelegation<M>>,
+    pub pending_undelegations: ManagedVec<M, Undelegation<M>>,
+    pub withdrawable_amount: BigUint<M>,
+}
+
+#[derive(TopEncode, TopDecode, NestedEncode, NestedDecode, TypeAbi, Clone, PartialEq, Eq, Debug)]
+pub struct RateSnapshot<M: ManagedTypeApi> {
+    pub epoch: u64,
+    pub token_price: BigUint<M>,
+    pub total_egld_staked: BigUint<M>,
+    pub liquid_token_supply: BigUint<M>,
+    pub reserve_price: BigUint<M>,
+    pub egld_reserve: BigUint<M>,
+    pub reserve_points: BigUint<M>,
+    pub timestamp: u64,
+    pub cumulative_price: BigUint<M>,
+}
+
 #[multiversx_sc::module]
 pub trait ConfigModule:
     multiversx_sc_modules::default_issue_callbacks::DefaultIssueCallbacksModule
+    + events::EventsModule
 {
     #[only_owner]
     #[payable("EGLD")]
@@ -51,6 +161,56 @@ pub trait ConfigModule:
     #[storage_mapper("liquid_token_id")]
     fn liquid_token_id(&self) -> FungibleTokenMapper<Self::Api>;
 
+    #[only_owner]
+    #[payable("EGLD")]
+    #[endpoint(registerUndelegationToken)]
+    fn register_undelegation_token(
+        &self,
+        token_display_name: ManagedBuffer,
+        token_ticker: ManagedBuffer,
+    ) {
+        require!(!self.is_state_active(), ERROR_ACTIVE);
+        require!(self.undelegation_token_id().is_empty(), ERROR_TOKEN_ALREADY_SET);
+        let payment_amount = self.call_value().egld_value();
+        self.undelegation_token_id().issue_and_set_all_roles(
+            EsdtTokenType::NonFungible,
+            payment_amount.clone_value(),
+            token_display_name,
+            token_ticker,
+            0,
+            None,
+        );
+    }
+
+    #[only_owner]
+    #[payable("EGLD")]
+    #[endpoint(registerReserveToken)]
+    fn register_reserve_token(
+        &self,
+        token_display_name: ManagedBuffer,
+        token_ticker: ManagedBuffer,
+        num_decimals: usize,
+    ) {
+        require!(!self.is_state_active(), ERROR_ACTIVE);
+        require!(self.reserve_token_id().is_empty(), ERROR_TOKEN_ALREADY_SET);
+        let payment_amount = self.call_value().egld_value();
+        self.reserve_token_id().issue_and_set_all_roles(
+            payment_amount.clone_value(),
+            token_display_name,
+            token_ticker,
+            num_decimals,
+            None,
+        );
+    }
+
+    #[view(getReserveTokenId)]
+    #[storage_mapper("reserve_token_id")]
+    fn reserve_token_id(&self) -> FungibleTokenMapper<Self::Api>;
+
+    #[view(getUndelegationTokenId)]
+    #[storage_mapper("undelegation_token_id")]
+    fn undelegation_token_id(&self) -> NonFungibleTokenMapper<Self::Api>;
+
     #[view(getLiquidTokenSupply)]
     #[storage_mapper("liquid_token_supply")]
     fn liquid_token_supply(&self) -> SingleValueMapper<BigUint>;
@@ -58,17 +218,54 @@ pub trait ConfigModule:
     #[only_owner]
     #[endpoint(setStateActive)]
     fn set_state_active(&self) {
-        require!(!self.provider_address().is_empty(), ERROR_PROVIDER_NOT_SET);
+        require!(!self.is_state_deprecated(), ERROR_DEPRECATED);
+        require!(self.provider_address().is_empty(), ERROR_LEGACY_PROVIDER_NOT_IMPORTED);
+        require!(!self.providers().is_empty(), ERROR_PROVIDER_NOT_SET);
         require!(!self.liquid_token_id().is_empty(), ERROR_TOKEN_NOT_SET);
+        require!(!self.undelegation_token_id().is_empty(), ERROR_TOKEN_NOT_SET);
+        require!(!self.reserve_token_id().is_empty(), ERROR_TOKEN_NOT_SET);
         require!(!self.unbond_period().is_empty(), ERROR_UNBOND_PERIOD_NOT_SET);
+        // without vesting, the fees could be captured by adding reserve right before them
+        require!(self.reserve_fees_vesting_epochs().get() > 0, ERROR_VESTING_EPOCHS_NOT_SET);
 
         self.state().set(State::Active);
+        self.emit_set_state_event();
     }
 
     #[only_owner]
     #[endpoint(setStateInactive)]
     fn set_state_inactive(&self) {
+        require!(!self.is_state_deprecated(), ERROR_DEPRECATED);
+
         self.state().set(State::Inactive);
+        self.emit_set_state_event();
+    }
+
+    // deposits are blocked, users can still exit
+    #[only_owner]
+    #[endpoint(setStateExitOnly)]
+    fn set_state_exit_only(&self) {
+        require!(!self.is_state_deprecated(), ERROR_DEPRECATED);
+
+        self.state().set(State::ExitOnly);
+        self.emit_set_state_event();
+    }
+
+    // same as exit only, but final
+    #[only_owner]
+    #[endpoint(setStateDeprecated)]
+    fn set_state_deprecated(&self) {
+        self.state().set(State::Deprecated);
+        self.emit_set_state_event();
+    }
+
+    fn emit_set_state_event(&self) {
+        self.set_state_event(
+            &self.blockchain().get_caller(),
+            self.state().get(),
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
     }
 
     #[inline]
@@ -77,27 +274,185 @@ pub trait ConfigModule:
         state == State::Active
     }
 
+    #[inline]
+    fn is_state_deprecated(&self) -> bool {
+        let state = self.state().get();
+        state == State::Deprecated
+    }
+
+    #[inline]
+    fn is_exit_allowed(&self) -> bool {
+        let state = self.state().get();
+        state == State::Active || state == State::ExitOnly || state == State::Deprecated
+    }
+
     #[view(getState)]
     #[storage_mapper("state")]
     fn state(&self) -> SingleValueMapper<State>;
 
-    #[only_owner]
-    #[endpoint(setProviderAddress)]
-    fn set_provider_address(self, address: ManagedAddress) {
-        require!(!self.is_state_active(), ERROR_ACTIVE);
+    // providers
 
+    #[only_owner]
+    #[endpoint(addProvider)]
+    fn add_provider(&self, address: ManagedAddress, weight: u64) {
+        require!(weight > 0, ERROR_INCORRECT_WEIGHT);
         require!(
-            self.provider_address().is_empty(),
+            !self.providers().contains_key(&address),
             ERROR_PROVIDER_ALREADY_SET
         );
 
-        self.provider_address().set(address);
+        self.providers().insert(
+            address.clone(),
+            ProviderConfig {
+                weight,
+                total_staked: BigUint::zero(),
+            },
+        );
+        self.emit_set_provider_event(&address, weight);
+    }
+
+    #[only_owner]
+    #[endpoint(setProviderWeight)]
+    fn set_provider_weight(&self, address: ManagedAddress, weight: u64) {
+        let mut provider = self.get_provider(&address);
+        provider.weight = weight;
+        self.providers().insert(address.clone(), provider);
+        self.emit_set_provider_event(&address, weight);
+    }
+
+    fn emit_set_provider_event(&self, address: &ManagedAddress, weight: u64) {
+        self.set_provider_event(
+            &self.blockchain().get_caller(),
+            address,
+            weight,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    #[only_owner]
+    #[endpoint(removeProvider)]
+    fn remove_provider(&self, address: ManagedAddress) {
+        require!(
+            self.migration_source().is_empty(),
+            ERROR_MIGRATION_IN_PROGRESS
+        );
+
+        let provider = self.get_provider(&address);
+        require!(
+            provider.total_staked == 0 && self.lprovider_undelegations(&address).is_empty(),
+            ERROR_PROVIDER_NOT_EMPTY
+        );
+
+        self.delete_provider(&address);
+        self.remove_provider_event(
+            &self.blockchain().get_caller(),
+            &address,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
     }
 
-    #[view(getProviderAddress)]
+    fn delete_provider(&self, address: &ManagedAddress) {
+        self.providers().remove(address);
+        self.claimable_rewards_epoch(address).clear();
+        self.provider_pending_calls(address).clear();
+        self.provider_calls_nonce(address).clear();
+    }
+
+    fn get_provider(&self, address: &ManagedAddress) -> ProviderConfig<Self::Api> {
+        match self.providers().get(address) {
+            Some(provider) => provider,
+            None => sc_panic!(ERROR_PROVIDER_NOT_FOUND),
+        }
+    }
+
+    fn add_provider_stake(&self, address: &ManagedAddress, amount: &BigUint) {
+        let mut provider = self.get_provider(address);
+        provider.total_staked += amount;
+        self.providers().insert(address.clone(), provider);
+    }
+
+    fn remove_provider_stake(&self, address: &ManagedAddress, amount: &BigUint) {
+        let mut provider = self.get_provider(address);
+        provider.total_staked -= amount;
+        self.providers().insert(address.clone(), provider);
+    }
+
+    // called before every async call that changes the provider's stake or undelegations
+    fn start_provider_call(&self, address: &ManagedAddress) {
+        self.provider_pending_calls(address)
+            .update(|value| *value += 1);
+        self.provider_calls_nonce(address)
+            .update(|value| *value += 1);
+    }
+
+    // called first in the callbacks of those calls
+    fn end_provider_call(&self, address: &ManagedAddress) {
+        self.provider_pending_calls(address)
+            .update(|value| *value = value.saturating_sub(1));
+    }
+
+    #[only_owner]
+    #[endpoint(setReconcileTolerance)]
+    fn set_reconcile_tolerance(&self, tolerance: u64) {
+        require!(tolerance < MAX_PERCENT, ERROR_INCORRECT_TOLERANCE);
+
+        self.reconcile_tolerance().set(tolerance);
+        self.set_reconcile_tolerance_event(
+            &self.blockchain().get_caller(),
+            tolerance,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    // max difference between the provider's active stake and the tracked stake, in basis points
+    #[view(getReconcileTolerance)]
+    #[storage_mapper("reconcile_tolerance")]
+    fn reconcile_tolerance(&self) -> SingleValueMapper<u64>;
+
+    #[view(getProviders)]
+    #[storage_mapper("providers")]
+    fn providers(&self) -> MapMapper<ManagedAddress, ProviderConfig<Self::Api>>;
+
+    // async calls sent to the provider and not yet returned, reconcile waits for them
+    #[view(getProviderPendingCalls)]
+    #[storage_mapper("provider_pending_calls")]
+    fn provider_pending_calls(&self, provider: &ManagedAddress) -> SingleValueMapper<u64>;
+
+    // increased with every call, so reconcile can tell if another call was sent meanwhile
+    #[storage_mapper("provider_calls_nonce")]
+    fn provider_calls_nonce(&self, provider: &ManagedAddress) -> SingleValueMapper<u64>;
+
+    // legacy single provider, only used by importLegacyProvider
+    #[view(getLegacyProviderAddress)]
     #[storage_mapper("provider_address")]
     fn provider_address(&self) -> SingleValueMapper<ManagedAddress>;
 
+    #[view(getProviderUndelegations)]
+    #[storage_mapper("lprovider_undelegations")]
+    fn lprovider_undelegations(
+        &self,
+        provider: &ManagedAddress,
+    ) -> LinkedListMapper<Undelegation<Self::Api>>;
+
+    #[view(getMigrationSource)]
+    #[storage_mapper("migration_source")]
+    fn migration_source(&self) -> SingleValueMapper<ManagedAddress>;
+
+    #[view(getMigrationTarget)]
+    #[storage_mapper("migration_target")]
+    fn migration_target(&self) -> SingleValueMapper<ManagedAddress>;
+
+    #[view(getMigrationUndelegations)]
+    #[storage_mapper("lmigration_undelegations")]
+    fn lmigration_undelegations(&self) -> LinkedListMapper<Undelegation<Self::Api>>;
+
+    #[view(getEgldToMigrate)]
+    #[storage_mapper("egld_to_migrate")]
+    fn egld_to_migrate(&self) -> SingleValueMapper<BigUint>;
+
     #[view(getUnbondPeriod)]
     #[storage_mapper("unbond_period")]
     fn unbond_period(&self) -> SingleValueMapper<u64>;
@@ -113,10 +468,17 @@ pub trait ConfigModule:
         require!(self.unbond_period().get() == 0, ERROR_UNBOND_PERIOD_ALREADY_SET);
 
         self.unbond_period().set(period);
+        self.set_unbond_period_event(
+            &self.blockchain().get_caller(),
+            period,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
     }
 
     // delegation
 
+    // legacy per user undelegations, only used by migrateUndelegations
     #[view(getUserUndelegations)]
     #[storage_mapper("luser_undelegations")]
     fn luser_undelegations(
@@ -128,11 +490,9 @@ pub trait ConfigModule:
     #[storage_mapper("total_egld_staked")]
     fn total_egld_staked(&self) -> SingleValueMapper<BigUint>;
 
-    #[storage_mapper("claimable_rewards_amount")]
-    fn claimable_rewards_amount(&self) -> SingleValueMapper<BigUint>;
-
+    // epoch of the last rewards claim
     #[storage_mapper("claimable_rewards_epoch")]
-    fn claimable_rewards_epoch(&self) -> SingleValueMapper<u64>;
+    fn claimable_rewards_epoch(&self, provider: &ManagedAddress) -> SingleValueMapper<u64>;
 
     #[view(getUserWithdrawnEgld)]
     #[storage_mapper("user_withdrawn_egld")]
@@ -146,6 +506,10 @@ pub trait ConfigModule:
     #[storage_mapper("ltotal_user_undelegations")]
     fn ltotal_user_undelegations(&self) -> LinkedListMapper<Undelegation<Self::Api>>;
 
+    #[view(getEgldToDelegate)]
+    #[storage_mapper("egld_to_delegate")]
+    fn egld_to_delegate(&self) -> SingleValueMapper<BigUint>;
+
     #[storage_mapper("egld_to_undelegate")]
     fn egld_to_undelegate(&self) -> SingleValueMapper<BigUint>;
 
@@ -167,6 +531,7 @@ pub trait ConfigModule:
     #[storage_mapper("lreserve_undelegations")]
     fn lreserve_undelegations(&self) -> LinkedListMapper<Undelegation<Self::Api>>;
 
+    // legacy per user reserve points, only used by migrateReserve
     #[view(getUsersReservePoints)]
     #[storage_mapper("users_reserve_points")]
     fn users_reserve_points(&self, user: &ManagedAddress) -> SingleValueMapper<BigUint>;
@@ -174,19 +539,73 @@ pub trait ConfigModule:
     #[only_owner]
     #[endpoint(setUndelegateNowFee)]
     fn set_undelegate_now_fee(&self, new_fee: u64) {
-        require!(!self.is_state_active(), ERROR_ACTIVE);
         require!(new_fee < MAX_PERCENT, ERROR_INCORRECT_FEE);
 
         self.undelegate_now_fee().set(new_fee);
+        self.emit_set_undelegate_now_fee_event();
     }
 
     #[view(getUndelegateNowFee)]
     #[storage_mapper("undelegate_now_fee")]
     fn undelegate_now_fee(&self) -> SingleValueMapper<u64>;
 
+    #[only_owner]
+    #[endpoint(setUndelegateNowMaxFee)]
+    fn set_undelegate_now_max_fee(&self, new_max_fee: u64) {
+        require!(new_max_fee < MAX_PERCENT, ERROR_INCORRECT_FEE);
+        require!(
+            new_max_fee >= self.undelegate_now_fee().get(),
+            ERROR_INCORRECT_MAX_FEE
+        );
+
+        self.undelegate_now_max_fee().set(new_max_fee);
+        self.emit_set_undelegate_now_fee_event();
+    }
+
+    fn emit_set_undelegate_now_fee_event(&self) {
+        self.set_undelegate_now_fee_event(
+            &self.blockchain().get_caller(),
+            self.undelegate_now_fee().get(),
+            self.undelegate_now_max_fee().get(),
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    #[view(getUndelegateNowMaxFee)]
+    #[storage_mapper("undelegate_now_max_fee")]
+    fn undelegate_now_max_fee(&self) -> SingleValueMapper<u64>;
+
+    // the fee rises linearly from the base fee (full reserve) to the max fee (empty reserve),
+    // averaged over the reserve used by the amount, so splitting an exit doesn't lower the fee
+    #[view(getUndelegateNowFeeQuote)]
+    fn get_undelegate_now_fee_quote(&self, egld_amount: &BigUint) -> u64 {
+        let fee = self.undelegate_now_fee().get();
+        let max_fee = self.undelegate_now_max_fee().get();
+        let egld_reserve = self.get_vested_egld_reserve();
+        if max_fee <= fee || egld_reserve == 0 {
+            return fee
+        }
+
+        let available_egld_reserve = self.available_egld_reserve().get();
+        let used_before = if egld_reserve > available_egld_reserve {
+            &egld_reserve - &available_egld_reserve
+        } else {
+            BigUint::zero()
+        };
+        let mut used_after = &used_before + egld_amount;
+        if used_after > egld_reserve {
+            used_after = egld_reserve.clone();
+        }
+        let avg_used = (used_before + used_after) / 2u64;
+        let extra_fee = avg_used * (max_fee - fee) / egld_reserve;
+
+        fee + extra_fee.to_u64().unwrap_or_default()
+    }
+
     #[view(getReservePointsAmount)]
     fn get_reserve_points_amount(&self, egld_amount: &BigUint) -> BigUint {
-        let egld_reserve = self.egld_reserve().get();
+        let egld_reserve = self.get_vested_egld_reserve();
         let reserve_points = self.reserve_points().get();
         let mut user_reserve_points = egld_amount.clone();
         if egld_reserve > 0 {
@@ -202,7 +621,7 @@ pub trait ConfigModule:
 
     #[view(getReserveEgldAmount)]
     fn get_reserve_egld_amount(&self, points_amount: &BigUint) -> BigUint {
-        let egld_reserve = self.egld_reserve().get();
+        let egld_reserve = self.get_vested_egld_reserve();
         let reserve_points = self.reserve_points().get();
         let mut user_egld_amount = points_amount.clone();
         if reserve_points > 0 {
@@ -219,21 +638,1069 @@ pub trait ConfigModule:
         self.get_reserve_egld_amount(&user_points)
     }
 
-    #[storage_mapper("add_reserve_epoch")]
-    fn add_reserve_epoch(&self, user: &ManagedAddress) -> SingleValueMapper<u64>;
+    // reserve fees vesting
+
+    #[only_owner]
+    #[endpoint(setReserveFeesVestingEpochs)]
+    fn set_reserve_fees_vesting_epochs(&self, epochs: u64) {
+        require!(
+            epochs > 0 && epochs <= MAX_VESTING_EPOCHS,
+            ERROR_INCORRECT_VESTING_EPOCHS
+        );
+
+        self.vest_reserve_fees();
+        self.reserve_fees_vesting_epochs().set(epochs);
+        self.set_reserve_fees_vesting_epochs_event(
+            &self.blockchain().get_caller(),
+            epochs,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    // the fees are added to the reserve linearly, so they go to the long term reserve providers.
+    // each epoch's fees vest on their own schedule, so new fees don't delay the earlier ones
+    fn add_reserve_fees(&self, fees: &BigUint) {
+        let boost_fees = self.distribute_reserve_boost(fees);
+        let fees = &(fees - &boost_fees);
+        self.vest_reserve_fees();
+        if fees == &0 {
+            return
+        }
+
+        self.unvested_reserve_fees()
+            .update(|value| *value += fees);
+        let end_epoch = self.blockchain().get_block_epoch() + self.reserve_fees_vesting_epochs().get();
+        let batches = self.reserve_fees_batches();
+        let mut clone_batches = self.reserve_fees_batches();
+        for node in batches.iter() {
+            let node_id = node.get_node_id();
+            let mut batch = node.into_value();
+            if batch.end_epoch == end_epoch {
+                batch.amount += fees;
+                clone_batches.set_node_value_by_id(node_id, batch);
+                return
+            }
+        }
+        clone_batches.push_back(ReserveFeesBatch {
+            amount: fees.clone(),
+            end_epoch,
+        });
+    }
+
+    fn vest_reserve_fees(&self) {
+        let current_epoch = self.blockchain().get_block_epoch();
+        let last_vest_epoch = self.reserve_fees_last_vest_epoch().get();
+        if current_epoch <= last_vest_epoch {
+            return
+        }
+
+        let batches = self.reserve_fees_batches();
+        let mut clone_batches = self.reserve_fees_batches();
+        let mut vested_fees = BigUint::zero();
+        for node in batches.iter() {
+            let node_id = node.get_node_id();
+            let mut batch = node.into_value();
+            let vested_amount = self.get_vested_batch_amount(&batch, current_epoch, last_vest_epoch);
+            if vested_amount == batch.amount {
+                clone_batches.remove_node_by_id(node_id);
+            } else {
+                batch.amount -= &vested_amount;
+                clone_batches.set_node_value_by_id(node_id, batch);
+            }
+            vested_fees += vested_amount;
+        }
+        if vested_fees > 0 {
+            self.unvested_reserve_fees()
+                .update(|value| *value -= &vested_fees);
+            self.egld_reserve()
+                .update(|value| *value += vested_fees);
+        }
+        self.reserve_fees_last_vest_epoch()
+            .set(current_epoch);
+    }
+
+    // losses are covered by the vested reserve first, then by the unvested fees
+    fn remove_reserve_egld(&self, amount: &BigUint) {
+        self.vest_reserve_fees();
+        let egld_reserve = self.egld_reserve().get();
+        if amount > &egld_reserve {
+            self.egld_reserve().clear();
+            self.remove_unvested_reserve_fees(&(amount - &egld_reserve));
+        } else {
+            self.egld_reserve()
+                .update(|value| *value -= amount);
+        }
+    }
+
+    // every batch is cut in the same proportion and keeps its schedule
+    fn remove_unvested_reserve_fees(&self, amount: &BigUint) {
+        let unvested_fees = self.unvested_reserve_fees().get();
+        let remaining_fees = &unvested_fees - amount;
+        let batches = self.reserve_fees_batches();
+        let mut clone_batches = self.reserve_fees_batches();
+        let mut total_amount = BigUint::zero();
+        for node in batches.iter() {
+            let node_id = node.get_node_id();
+            let mut batch = node.into_value();
+            batch.amount = batch.amount * &remaining_fees / &unvested_fees;
+            if batch.amount == 0 {
+                clone_batches.remove_node_by_id(node_id);
+            } else {
+                total_amount += &batch.amount;
+                clone_batches.set_node_value_by_id(node_id, batch);
+            }
+        }
+
+        // the rounding dust is vested right away
+        self.egld_reserve()
+            .update(|value| *value += &remaining_fees - &total_amount);
+        self.unvested_reserve_fees().set(total_amount);
+    }
+
+    fn get_vested_reserve_fees(&self) -> BigUint {
+        let current_epoch = self.blockchain().get_block_epoch();
+        let last_vest_epoch = self.reserve_fees_last_vest_epoch().get();
+        let mut vested_fees = BigUint::zero();
+        if current_epoch <= last_vest_epoch {
+            return vested_fees
+        }
+
+        for node in self.reserve_fees_batches().iter() {
+            vested_fees += self.get_vested_batch_amount(&node.into_value(), current_epoch, last_vest_epoch);
+        }
+
+        vested_fees
+    }
+
+    fn get_vested_batch_amount(
+        &self,
+        batch: &ReserveFeesBatch<Self::Api>,
+        current_epoch: u64,
+        last_vest_epoch: u64,
+    ) -> BigUint {
+        if current_epoch >= batch.end_epoch {
+            return batch.amount.clone()
+        }
+
+        &batch.amount * (current_epoch - last_vest_epoch) / (batch.end_epoch - last_vest_epoch)
+    }
+
+    // amount, end epoch
+    #[view(getReserveFeesBatches)]
+    fn get_reserve_fees_batches(&self) -> MultiValueEncoded<MultiValue2<BigUint, u64>> {
+        let mut result = MultiValueEncoded::new();
+        for node in self.reserve_fees_batches().iter() {
+            let batch = node.into_value();
+            result.push((batch.amount, batch.end_epoch).into());
+        }
+
+        result
+    }
+
+    // the reserve including the fees vested since the last update
+    fn get_vested_egld_reserve(&self) -> BigUint {
+        self.egld_reserve().get() + self.get_vested_reserve_fees()
+    }
+
+    // reserve lock tiers
+
+    #[only_owner]
+    #[endpoint(setReserveLockTier)]
+    fn set_reserve_lock_tier(&self, lock_epochs: u64, multiplier: u64) {
+        require!(lock_epochs > 0, ERROR_INCORRECT_LOCK_EPOCHS);
+        require!(
+            (MAX_PERCENT..=MAX_LOCK_MULTIPLIER).contains(&multiplier),
+            ERROR_INCORRECT_LOCK_MULTIPLIER
+        );
+
+        // existing locks keep their multiplier
+        self.reserve_lock_tiers().insert(lock_epochs, multiplier);
+        self.set_reserve_lock_tier_event(
+            &self.blockchain().get_caller(),
+            lock_epochs,
+            multiplier,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    #[only_owner]
+    #[endpoint(removeReserveLockTier)]
+    fn remove_reserve_lock_tier(&self, lock_epochs: u64) {
+        require!(
+            self.reserve_lock_tiers().remove(&lock_epochs).is_some(),
+            ERROR_LOCK_TIER_NOT_FOUND
+        );
+
+        self.remove_reserve_lock_tier_event(
+            &self.blockchain().get_caller(),
+            lock_epochs,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    #[view(getReserveLockTiers)]
+    fn get_reserve_lock_tiers(&self) -> MultiValueEncoded<MultiValue2<u64, u64>> {
+        let mut tiers = MultiValueEncoded::new();
+        for (lock_epochs, multiplier) in self.reserve_lock_tiers().iter() {
+            tiers.push((lock_epochs, multiplier).into());
+        }
+
+        tiers
+    }
+
+    fn lock_reserve_points(&self, user: &ManagedAddress, reserve_points: &BigUint, lock_epochs: u64) {
+        let multiplier = self.reserve_lock_tiers().get(&lock_epochs);
+        require!(multiplier.is_some(), ERROR_LOCK_TIER_NOT_FOUND);
+
+        let mut position = if self.locked_reserve(user).is_empty() {
+            LockedReserve {
+                reserve_points: BigUint::zero(),
+                lock_epochs,
+                multiplier: multiplier.unwrap(),
+                unlock_epoch: 0,
+                boost_index: self.reserve_boost_index().get(),
+                boost_rewards: BigUint::zero(),
+            }
+        } else {
+            self.get_updated_locked_reserve(user)
+        };
+        require!(position.lock_epochs == lock_epochs, ERROR_DIFFERENT_LOCK_TIER);
+
+        // adding to a lock restarts it
+        let old_boost_weight = self.get_boost_weight(&position.reserve_points, position.multiplier);
+        position.reserve_points += reserve_points;
+        position.unlock_epoch = self.blockchain().get_block_epoch() + lock_epochs;
+        let new_boost_weight = self.get_boost_weight(&position.reserve_points, position.multiplier);
+        self.reserve_boost_weight()
+            .update(|value| *value += new_boost_weight - old_boost_weight);
+        self.locked_reserve(user).set(position);
+        self.locked_reserve_users().insert(user.clone());
+    }
+
+    // the boost weight is the part of the locked points above 1x
+    fn get_boost_weight(&self, reserve_points: &BigUint, multiplier: u64) -> BigUint {
+        reserve_points * (multiplier - MAX_PERCENT) / MAX_PERCENT
+    }
+
+    // the fees are shared by points + boost weight, the boost part goes to the locked positions
+    fn distribute_reserve_boost(&self, fees: &BigUint) -> BigUint {
+        let boost_weight = self.reserve_boost_weight().get();
+        if boost_weight == 0 {
+            return BigUint::zero()
+        }
+
+        let boost_fees = fees * &boost_weight / (self.reserve_points().get() + &boost_weight);
+        self.reserve_boost_index()
+            .update(|value| *value += &boost_fees * BOOST_INDEX_PRECISION / &boost_weight);
+        self.reserve_boost_pool()
+            .update(|value| *value += &boost_fees);
+
+        boost_fees
+    }
+
+    #[view(getLockedReserve)]
+    fn get_locked_reserve(&self, user: &ManagedAddress) -> OptionalValue<LockedReserve<Self::Api>> {
+        if self.locked_reserve(user).is_empty() {
+            OptionalValue::None
+        } else {
+            OptionalValue::Some(self.get_updated_locked_reserve(user))
+        }
+    }
+
+    // the locked position with the boost rewards earned so far
+    fn get_updated_locked_reserve(&self, user: &ManagedAddress) -> LockedReserve<Self::Api> {
+        let mut position = self.locked_reserve(user).get();
+        let boost_index = self.reserve_boost_index().get();
+        position.boost_rewards += self.get_boost_weight(&position.reserve_points, position.multiplier) *
+            (&boost_index - &position.boost_index) / BOOST_INDEX_PRECISION;
+        position.boost_index = boost_index;
+
+        position
+    }
+
+    #[storage_mapper("reserve_lock_tiers")]
+    fn reserve_lock_tiers(&self) -> MapMapper<u64, u64>;
+
+    #[storage_mapper("locked_reserve")]
+    fn locked_reserve(&self, user: &ManagedAddress) -> SingleValueMapper<LockedReserve<Self::Api>>;
+
+    #[view(getReserveBoostWeight)]
+    #[storage_mapper("reserve_boost_weight")]
+    fn reserve_boost_weight(&self) -> SingleValueMapper<BigUint>;
+
+    #[storage_mapper("reserve_boost_index")]
+    fn reserve_boost_index(&self) -> SingleValueMapper<BigUint>;
+
+    // boost rewards not yet claimed, kept in the available reserve
+    #[view(getReserveBoostPool)]
+    #[storage_mapper("reserve_boost_pool")]
+    fn reserve_boost_pool(&self) -> SingleValueMapper<BigUint>;
+
+    #[view(getReserveFeesVestingEpochs)]
+    #[storage_mapper("reserve_fees_vesting_epochs")]
+    fn reserve_fees_vesting_epochs(&self) -> SingleValueMapper<u64>;
+
+    #[view(getUnvestedReserveFees)]
+    #[storage_mapper("unvested_reserve_fees")]
+    fn unvested_reserve_fees(&self) -> SingleValueMapper<BigUint>;
+
+    #[storage_mapper("reserve_fees_batches")]
+    fn reserve_fees_batches(&self) -> LinkedListMapper<ReserveFeesBatch<Self::Api>>;
+
+    #[storage_mapper("reserve_fees_last_vest_epoch")]
+    fn reserve_fees_last_vest_epoch(&self) -> SingleValueMapper<u64>;
+
+    // undelegation orders
+
+    // order id, owner, liquid tokens, max fee
+    #[view(getUndelegationOrders)]
+    fn get_undelegation_orders(
+        &self,
+        from: usize,
+        size: usize,
+    ) -> MultiValueEncoded<MultiValue4<u64, ManagedAddress, BigUint, u64>> {
+        let mut result = MultiValueEncoded::new();
+        let order_ids = self.undelegation_order_ids();
+        for index in self.get_page_indexes(order_ids.len(), from, size) {
+            let order_id = order_ids.get_by_index(index);
+            let order = self.undelegation_order(order_id).get();
+            result.push((order_id, order.owner, order.ls_amount, order.max_fee).into());
+        }
+
+        result
+    }
+
+    #[view(getUndelegationOrder)]
+    #[storage_mapper("undelegation_order")]
+    fn undelegation_order(&self, order_id: u64) -> SingleValueMapper<UndelegationOrder<Self::Api>>;
+
+    #[storage_mapper("undelegation_order_ids")]
+    fn undelegation_order_ids(&self) -> UnorderedSetMapper<u64>;
+
+    #[storage_mapper("last_undelegation_order_id")]
+    fn last_undelegation_order_id(&self) -> SingleValueMapper<u64>;
+
+    // reserve exits
+
+    // the reserve exits are filled in FIFO order as the reserve becomes available
+    #[view(getReserveExits)]
+    fn get_reserve_exits(
+        &self,
+        from: usize,
+        size: usize,
+    ) -> MultiValueEncoded<MultiValue4<u64, ManagedAddress, BigUint, u64>> {
+        let mut result = MultiValueEncoded::new();
+        for node in self.reserve_exits().iter().skip(from).take(size) {
+            let exit = node.into_value();
+            result.push((exit.id, exit.owner, exit.reserve_points, exit.epoch).into());
+        }
+
+        result
+    }
+
+    #[view(getReserveExitsCount)]
+    fn get_reserve_exits_count(&self) -> usize {
+        self.reserve_exits().len()
+    }
+
+    fn queue_reserve_exit(&self, user: &ManagedAddress, reserve_points: &BigUint) {
+        let exit_id = self.last_reserve_exit_id().update(|id| {
+            *id += 1;
+            *id
+        });
+        let current_epoch = self.blockchain().get_block_epoch();
+        let node = self.reserve_exits().push_back(ReserveExit {
+            id: exit_id,
+            owner: user.clone(),
+            reserve_points: reserve_points.clone(),
+            epoch: current_epoch,
+        });
+        self.reserve_exit_node_id(exit_id).set(node.get_node_id());
+        self.queued_reserve_points()
+            .update(|value| *value += reserve_points);
+        self.user_queued_reserve_points(user)
+            .update(|value| *value += reserve_points);
+        self.reserve_exit_event(user, exit_id, reserve_points, current_epoch, &self.token_price());
+    }
+
+    #[storage_mapper("reserve_exits")]
+    fn reserve_exits(&self) -> LinkedListMapper<ReserveExit<Self::Api>>;
+
+    #[storage_mapper("last_reserve_exit_id")]
+    fn last_reserve_exit_id(&self) -> SingleValueMapper<u64>;
+
+    // lets an exit be cancelled without walking the queue
+    #[storage_mapper("reserve_exit_node_id")]
+    fn reserve_exit_node_id(&self, exit_id: u64) -> SingleValueMapper<u32>;
+
+    // the queued points are still part of the reserve until filled
+    #[view(getQueuedReservePoints)]
+    #[storage_mapper("queued_reserve_points")]
+    fn queued_reserve_points(&self) -> SingleValueMapper<BigUint>;
+
+    #[view(getUserQueuedReservePoints)]
+    #[storage_mapper("user_queued_reserve_points")]
+    fn user_queued_reserve_points(&self, user: &ManagedAddress) -> SingleValueMapper<BigUint>;
+
+    // filled exits are claimed by their owners
+    #[view(getReserveExitClaimable)]
+    #[storage_mapper("reserve_exit_claimable")]
+    fn reserve_exit_claimable(&self, user: &ManagedAddress) -> SingleValueMapper<BigUint>;
+
+    // registries
+
+    // the reserve providers and the undelegation users are the addresses that received the
+    // positions. the tokens are transferable, so a position sent to another address stays listed
+    // under the original address until it is removed or withdrawn.
+    // the legacy address keyed positions can't be enumerated on chain, so the owner has to
+    // register them and the lists only shrink as they migrate
+
+    fn track_reserve_points(&self, user: &ManagedAddress, reserve_points: &BigUint) {
+        self.reserve_provider_points(user)
+            .update(|value| *value += reserve_points);
+        self.reserve_providers().insert(user.clone());
+    }
+
+    // removing more points than registered (e.g. received from another address) clears the entry
+    fn untrack_reserve_points(&self, user: &ManagedAddress, reserve_points: &BigUint) {
+        let provider_points = self.reserve_provider_points(user).get();
+        if provider_points > *reserve_points {
+            self.reserve_provider_points(user)
+                .set(provider_points - reserve_points);
+            return
+        }
+
+        self.reserve_provider_points(user).clear();
+        self.reserve_providers().swap_remove(user);
+    }
+
+    fn track_undelegation_token(&self, user: &ManagedAddress, nonce: u64) {
+        self.undelegation_tokens().insert(nonce);
+        self.undelegation_token_user(nonce).set(user);
+        self.user_undelegation_tokens(user).insert(nonce);
+        self.undelegation_users().insert(user.clone());
+    }
+
+    fn untrack_undelegation_token(&self, nonce: u64) {
+        self.undelegation_tokens().swap_remove(&nonce);
+        if self.undelegation_token_user(nonce).is_empty() {
+            return
+        }
+
+        let user = self.undelegation_token_user(nonce).take();
+        let mut user_tokens = self.user_undelegation_tokens(&user);
+        user_tokens.swap_remove(&nonce);
+        if user_tokens.is_empty() {
+            self.undelegation_users().swap_remove(&user);
+        }
+    }
+
+    // user, reserve points
+    #[view(getReserveProviders)]
+    fn get_reserve_providers(
+        &self,
+        from: usize,
+        size: usize,
+    ) -> MultiValueEncoded<MultiValue2<ManagedAddress, BigUint>> {
+        let mut result = MultiValueEncoded::new();
+        let users = self.reserve_providers();
+        for index in self.get_page_indexes(users.len(), from, size) {
+            let user = users.get_by_index(index);
+            let points = self.reserve_provider_points(&user).get();
+            result.push((user, points).into());
+        }
+
+        result
+    }
+
+    // user, undelegated amount after losses
+    #[view(getUndelegationUsers)]
+    fn get_undelegation_users(
+        &self,
+        from: usize,
+        size: usize,
+    ) -> MultiValueEncoded<MultiValue2<ManagedAddress, BigUint>> {
+        let mut result = MultiValueEncoded::new();
+        let users = self.undelegation_users();
+        for index in self.get_page_indexes(users.len(), from, size) {
+            let user = users.get_by_index(index);
+            let mut amount = BigUint::zero();
+            for nonce in self.user_undelegation_tokens(&user).iter() {
+                let undelegation: Undelegation<Self::Api> =
+                    self.undelegation_token_id().get_token_attributes(nonce);
+                amount += self.get_undelegation_token_amount(nonce, &undelegation);
+            }
+            result.push((user, amount).into());
+        }
+
+        result
+    }
+
+    // nonce, amount after losses, unbond epoch
+    #[view(getUserUndelegationTokens)]
+    fn get_user_undelegation_tokens(
+        &self,
+        user: ManagedAddress,
+    ) -> MultiValueEncoded<MultiValue3<u64, BigUint, u64>> {
+        let mut result = MultiValueEncoded::new();
+        for nonce in self.user_undelegation_tokens(&user).iter() {
+            let undelegation: Undelegation<Self::Api> =
+                self.undelegation_token_id().get_token_attributes(nonce);
+            let amount = self.get_undelegation_token_amount(nonce, &undelegation);
+            result.push((nonce, amount, undelegation.unbond_epoch).into());
+        }
+
+        result
+    }
+
+    #[only_owner]
+    #[endpoint(registerLegacyUsers)]
+    fn register_legacy_users(&self, users: MultiValueEncoded<ManagedAddress>) {
+        for user in users.into_iter() {
+            if self.users_reserve_points(&user).get() > 0 {
+                self.legacy_reserve_users().insert(user.clone());
+            }
+            if !self.luser_undelegations(&user).is_empty() {
+                self.legacy_undelegation_users().insert(user);
+            }
+        }
+    }
+
+    // nonce, amount, amount after losses, unbond epoch
+    #[view(getUndelegationTokens)]
+    fn get_undelegation_tokens(
+        &self,
+        from: usize,
+        size: usize,
+    ) -> MultiValueEncoded<MultiValue4<u64, BigUint, BigUint, u64>> {
+        let mut result = MultiValueEncoded::new();
+        let nonces = self.undelegation_tokens();
+        for index in self.get_page_indexes(nonces.len(), from, size) {
+            let nonce = nonces.get_by_index(index);
+            let undelegation: Undelegation<Self::Api> =
+                self.undelegation_token_id().get_token_attributes(nonce);
+            let amount = self.get_undelegation_token_amount(nonce, &undelegation);
+            result.push((nonce, undelegation.amount, amount, undelegation.unbond_epoch).into());
+        }
+
+        result
+    }
+
+    // user, reserve points
+    #[view(getLegacyReserveUsers)]
+    fn get_legacy_reserve_users(
+        &self,
+        from: usize,
+        size: usize,
+    ) -> MultiValueEncoded<MultiValue2<ManagedAddress, BigUint>> {
+        let mut result = MultiValueEncoded::new();
+        let users = self.legacy_reserve_users();
+        for index in self.get_page_indexes(users.len(), from, size) {
+            let user = users.get_by_index(index);
+            let points = self.users_reserve_points(&user).get();
+            result.push((user, points).into());
+        }
+
+        result
+    }
+
+    // user, undelegated amount
+    #[view(getLegacyUndelegationUsers)]
+    fn get_legacy_undelegation_users(
+        &self,
+        from: usize,
+        size: usize,
+    ) -> MultiValueEncoded<MultiValue2<ManagedAddress, BigUint>> {
+        let mut result = MultiValueEncoded::new();
+        let users = self.legacy_undelegation_users();
+        for index in self.get_page_indexes(users.len(), from, size) {
+            let user = users.get_by_index(index);
+            let mut amount = BigUint::zero();
+            for node in self.luser_undelegations(&user).iter() {
+                amount += self.get_undelegation_amount_after_losses(&node.into_value(), 0);
+            }
+            result.push((user, amount).into());
+        }
+
+        result
+    }
+
+    // set mapper indexes start from 1
+    fn get_page_indexes(&self, len: usize, from: usize, size: usize) -> core::ops::Range<usize> {
+        let start = if from < len { from + 1 } else { len + 1 };
+        let end = if size < len + 1 - start { start + size } else { len + 1 };
+
+        start..end
+    }
+
+    #[view(getUndelegationTokensCount)]
+    fn get_undelegation_tokens_count(&self) -> usize {
+        self.undelegation_tokens().len()
+    }
+
+    #[storage_mapper("undelegation_tokens")]
+    fn undelegation_tokens(&self) -> UnorderedSetMapper<u64>;
+
+    #[view(getReserveProvidersCount)]
+    fn get_reserve_providers_count(&self) -> usize {
+        self.reserve_providers().len()
+    }
+
+    #[storage_mapper("reserve_providers")]
+    fn reserve_providers(&self) -> UnorderedSetMapper<ManagedAddress>;
+
+    #[storage_mapper("reserve_provider_points")]
+    fn reserve_provider_points(&self, user: &ManagedAddress) -> SingleValueMapper<BigUint>;
+
+    #[view(getUndelegationUsersCount)]
+    fn get_undelegation_users_count(&self) -> usize {
+        self.undelegation_users().len()
+    }
+
+    #[storage_mapper("undelegation_users")]
+    fn undelegation_users(&self) -> UnorderedSetMapper<ManagedAddress>;
+
+    #[storage_mapper("user_undelegation_tokens")]
+    fn user_undelegation_tokens(&self, user: &ManagedAddress) -> UnorderedSetMapper<u64>;
+
+    #[storage_mapper("undelegation_token_user")]
+    fn undelegation_token_user(&self, nonce: u64) -> SingleValueMapper<ManagedAddress>;
+
+    #[view(getLegacyReserveUsersCount)]
+    fn get_legacy_reserve_users_count(&self) -> usize {
+        self.legacy_reserve_users().len()
+    }
+
+    #[storage_mapper("legacy_reserve_users")]
+    fn legacy_reserve_users(&self) -> UnorderedSetMapper<ManagedAddress>;
+
+    #[view(getLegacyUndelegationUsersCount)]
+    fn get_legacy_undelegation_users_count(&self) -> usize {
+        self.legacy_undelegation_users().len()
+    }
+
+    #[storage_mapper("legacy_undelegation_users")]
+    fn legacy_undelegation_users(&self) -> UnorderedSetMapper<ManagedAddress>;
+
+    // the locked reserves are held by the contract, so their owners are known
+    // user, locked reserve points, unlock epoch
+    #[view(getLockedReserveUsers)]
+    fn get_locked_reserve_users(
+        &self,
+        from: usize,
+        size: usize,
+    ) -> MultiValueEncoded<MultiValue3<ManagedAddress, BigUint, u64>> {
+        let mut result = MultiValueEncoded::new();
+        let users = self.locked_reserve_users();
+        for index in self.get_page_indexes(users.len(), from, size) {
+            let user = users.get_by_index(index);
+            let position = self.locked_reserve(&user).get();
+            result.push((user, position.reserve_points, position.unlock_epoch).into());
+        }
+
+        result
+    }
+
+    #[view(getLockedReserveUsersCount)]
+    fn get_locked_reserve_users_count(&self) -> usize {
+        self.locked_reserve_users().len()
+    }
+
+    #[storage_mapper("locked_reserve_users")]
+    fn locked_reserve_users(&self) -> UnorderedSetMapper<ManagedAddress>;
+
+    // losses
+
+    #[only_owner]
+    #[endpoint(setReserveLossShare)]
+    fn set_reserve_loss_share(&self, share: u64) {
+        require!(share <= MAX_PERCENT, ERROR_INCORRECT_LOSS_SHARE);
+
+        self.reserve_loss_share().set(share);
+        self.set_reserve_loss_share_event(
+            &self.blockchain().get_caller(),
+            share,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    // part of a loss covered by the available reserve, the rest is shared pro rata
+    #[view(getReserveLossShare)]
+    #[storage_mapper("reserve_loss_share")]
+    fn reserve_loss_share(&self) -> SingleValueMapper<u64>;
+
+    // matured undelegations the provider didn't return, pending the owner confirmation
+    #[view(getUnpaidUndelegations)]
+    #[storage_mapper("unpaid_undelegations")]
+    fn unpaid_undelegations(&self, provider: &ManagedAddress) -> SingleValueMapper<BigUint>;
+
+    #[storage_mapper("unpaid_undelegations_epoch")]
+    fn unpaid_undelegations_epoch(&self, provider: &ManagedAddress) -> SingleValueMapper<u64>;
+
+    #[view(getTotalLosses)]
+    #[storage_mapper("total_losses")]
+    fn total_losses(&self) -> SingleValueMapper<BigUint>;
+
+    // losses not covered by the reserve, the holders or the undelegations
+    #[view(getUncoveredLosses)]
+    #[storage_mapper("uncovered_losses")]
+    fn uncovered_losses(&self) -> SingleValueMapper<BigUint>;
+
+    // a loss of unbonding stake only cuts the undelegations matured when it happened. each record
+    // keeps that epoch and the index after the loss, so the epochs are increasing
+    #[view(getUndelegationsLosses)]
+    #[storage_mapper("undelegations_losses")]
+    fn undelegations_losses(&self) -> VecMapper<UndelegationLoss<Self::Api>>;
+
+    // the number of loss records when the token was created, empty for tokens created before any loss
+    #[storage_mapper("undelegation_token_losses_count")]
+    fn undelegation_token_losses_count(&self, nonce: u64) -> SingleValueMapper<usize>;
+
+    fn get_undelegation_token_amount(&self, nonce: u64, undelegation: &Undelegation<Self::Api>) -> BigUint {
+        self.get_undelegation_amount_after_losses(
+            undelegation,
+            self.undelegation_token_losses_count(nonce).get(),
+        )
+    }
+
+    // only the records added after losses_count, with the epoch not before the unbond epoch, apply
+    fn get_undelegation_amount_after_losses(
+        &self,
+        undelegation: &Undelegation<Self::Api>,
+        losses_count: usize,
+    ) -> BigUint {
+        let losses = self.undelegations_losses();
+        let total_count = losses.len();
+        if losses_count >= total_count {
+            return undelegation.amount.clone()
+        }
+
+        let mut low = losses_count + 1;
+        let mut high = total_count + 1;
+        while low < high {
+            let middle = (low + high) / 2;
+            if losses.get(middle).cutoff_epoch >= undelegation.unbond_epoch {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        if low > total_count {
+            return undelegation.amount.clone()
+        }
+
+        let start_index = if low == 1 {
+            BigUint::from(LOSS_INDEX_PRECISION)
+        } else {
+            losses.get(low - 1).loss_index
+        };
+
+        &undelegation.amount * &losses.get(total_count).loss_index / start_index
+    }
+
+    // protocol fee
+
+    #[only_owner]
+    #[endpoint(setProtocolFee)]
+    fn set_protocol_fee(&self, new_fee: u64) {
+        require!(new_fee < MAX_PERCENT, ERROR_INCORRECT_FEE);
+
+        self.protocol_fee().set(new_fee);
+        self.set_protocol_fee_event(
+            &self.blockchain().get_caller(),
+            new_fee,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    #[view(getProtocolFee)]
+    #[storage_mapper("protocol_fee")]
+    fn protocol_fee(&self) -> SingleValueMapper<u64>;
+
+    #[only_owner]
+    #[endpoint(setTreasuryAddress)]
+    fn set_treasury_address(&self, address: ManagedAddress) {
+        self.treasury_address().set(&address);
+        self.set_treasury_address_event(
+            &self.blockchain().get_caller(),
+            &address,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    #[view(getTreasuryAddress)]
+    #[storage_mapper("treasury_address")]
+    fn treasury_address(&self) -> SingleValueMapper<ManagedAddress>;
+
+    #[view(getTotalProtocolFees)]
+    #[storage_mapper("total_protocol_fees")]
+    fn total_protocol_fees(&self) -> SingleValueMapper<BigUint>;
+
+    #[view(getTotalProtocolFeeTokens)]
+    #[storage_mapper("total_protocol_fee_tokens")]
+    fn total_protocol_fee_tokens(&self) -> SingleValueMapper<BigUint>;
+
+    // keeper rewards
+
+    #[only_owner]
+    #[endpoint(setKeeperRewards)]
+    fn set_keeper_rewards(&self, reward: BigUint, max_epoch_rewards: BigUint, rewards_share: u64) {
+        require!(rewards_share < MAX_PERCENT, ERROR_INCORRECT_KEEPER_SHARE);
+
+        self.keeper_reward().set(&reward);
+        self.keeper_max_epoch_rewards().set(&max_epoch_rewards);
+        self.keeper_rewards_share().set(rewards_share);
+        self.set_keeper_rewards_event(
+            &self.blockchain().get_caller(),
+            &reward,
+            &max_epoch_rewards,
+            rewards_share,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    // paid for each successful service call
+    #[view(getKeeperReward)]
+    #[storage_mapper("keeper_reward")]
+    fn keeper_reward(&self) -> SingleValueMapper<BigUint>;
+
+    #[view(getKeeperMaxEpochRewards)]
+    #[storage_mapper("keeper_max_epoch_rewards")]
+    fn keeper_max_epoch_rewards(&self) -> SingleValueMapper<BigUint>;
+
+    // part of the compounded rewards that funds the keeper rewards
+    #[view(getKeeperRewardsShare)]
+    #[storage_mapper("keeper_rewards_share")]
+    fn keeper_rewards_share(&self) -> SingleValueMapper<u64>;
+
+    #[view(getKeeperRewardsPool)]
+    #[storage_mapper("keeper_rewards_pool")]
+    fn keeper_rewards_pool(&self) -> SingleValueMapper<BigUint>;
+
+    #[view(getKeeperEpochRewards)]
+    #[storage_mapper("keeper_epoch_rewards")]
+    fn keeper_epoch_rewards(&self) -> SingleValueMapper<BigUint>;
+
+    #[storage_mapper("keeper_rewards_epoch")]
+    fn keeper_rewards_epoch(&self) -> SingleValueMapper<u64>;
+
+    #[storage_mapper("keeper_undelegate_all_epoch")]
+    fn keeper_undelegate_all_epoch(&self) -> SingleValueMapper<u64>;
+
+    // the rewards are credited in callbacks too, so they are claimed separately
+    #[view(getKeeperClaimableRewards)]
+    #[storage_mapper("keeper_claimable_rewards")]
+    fn keeper_claimable_rewards(&self, keeper: &ManagedAddress) -> SingleValueMapper<BigUint>;
+
+    // rate history
+
+    // called before any price change. the rates at the start of the epoch are kept as a snapshot
+    fn update_rate_history(&self) {
+        self.update_rate_accumulator();
+
+        let current_epoch = self.blockchain().get_block_epoch();
+        let slot = current_epoch % RATE_HISTORY_SIZE;
+        let snapshot_mapper = self.rate_snapshot(slot);
+        if !snapshot_mapper.is_empty() && snapshot_mapper.get().epoch == current_epoch {
+            return
+        }
+
+        snapshot_mapper.set(RateSnapshot {
+            epoch: current_epoch,
+            token_price: self.token_price(),
+            total_egld_staked: self.total_egld_staked().get(),
+            liquid_token_supply: self.liquid_token_supply().get(),
+            reserve_price: self.reserve_price(),
+            egld_reserve: self.egld_reserve().get(),
+            reserve_points: self.reserve_points().get(),
+            timestamp: self.blockchain().get_block_timestamp(),
+            cumulative_price: self.rate_cumulative_price().get(),
+        });
+    }
+
+    // the price held since the last update is added to the accumulator
+    fn update_rate_accumulator(&self) {
+        self.rate_cumulative_price().set(self.get_cumulative_price());
+        self.rate_accumulator_timestamp()
+            .set(self.blockchain().get_block_timestamp());
+    }
+
+    fn get_cumulative_price(&self) -> BigUint {
+        let cumulative_price = self.rate_cumulative_price().get();
+        let last_timestamp = self.rate_accumulator_timestamp().get();
+        let current_timestamp = self.blockchain().get_block_timestamp();
+        if last_timestamp == 0 || current_timestamp <= last_timestamp {
+            return cumulative_price
+        }
+
+        cumulative_price + self.token_price() * (current_timestamp - last_timestamp)
+    }
+
+    // called after a price change. moving more than allowed in an epoch leaves only exits allowed
+    fn check_price_move(&self) {
+        let max_price_change = self.max_price_change().get();
+        if max_price_change == 0 || !self.is_state_active() {
+            return
+        }
+
+        let current_epoch = self.blockchain().get_block_epoch();
+        let start_price = match self.get_rate_snapshot(current_epoch) {
+            OptionalValue::Some(snapshot) => snapshot.token_price,
+            OptionalValue::None => return,
+        };
+        let token_price = self.token_price();
+        let difference = if token_price > start_price {
+            &token_price - &start_price
+        } else {
+            &start_price - &token_price
+        };
+        if difference * MAX_PERCENT <= &start_price * max_price_change {
+            return
+        }
+
+        // only exits are allowed, so the users' funds are not frozen
+        self.state().set(State::ExitOnly);
+        self.price_move_pause_event(&start_price, &token_price, current_epoch);
+    }
+
+    #[only_owner]
+    #[endpoint(setMaxPriceChange)]
+    fn set_max_price_change(&self, max_price_change: u64) {
+        require!(max_price_change <= MAX_PERCENT, ERROR_INCORRECT_PRICE_CHANGE);
+
+        self.max_price_change().set(max_price_change);
+        self.set_max_price_change_event(
+            &self.blockchain().get_caller(),
+            max_price_change,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    // max price move in an epoch, 0 to disable
+    #[view(getMaxPriceChange)]
+    #[storage_mapper("max_price_change")]
+    fn max_price_change(&self) -> SingleValueMapper<u64>;
+
+    // time weighted average price over the last window_epochs
+    #[view(getTwapRate)]
+    fn get_twap_rate(&self, window_epochs: u64) -> BigUint {
+        let snapshot = match self.get_rate_snapshot_before(window_epochs) {
+            Some(snapshot) => snapshot,
+            None => sc_panic!(ERROR_NOT_ENOUGH_RATE_HISTORY),
+        };
+        let current_timestamp = self.blockchain().get_block_timestamp();
+        if current_timestamp <= snapshot.timestamp {
+            return self.token_price()
+        }
+
+        (self.get_cumulative_price() - snapshot.cumulative_price) / (current_timestamp - snapshot.timestamp)
+    }
+
+    #[view(getCumulativePrice)]
+    #[storage_mapper("rate_cumulative_price")]
+    fn rate_cumulative_price(&self) -> SingleValueMapper<BigUint>;
+
+    #[storage_mapper("rate_accumulator_timestamp")]
+    fn rate_accumulator_timestamp(&self) -> SingleValueMapper<u64>;
+
+    // the latest snapshot taken at least window_epochs ago
+    fn get_rate_snapshot_before(&self, window_epochs: u64) -> Option<RateSnapshot<Self::Api>> {
+        let current_epoch = self.blockchain().get_block_epoch();
+        if window_epochs == 0 || window_epochs >= RATE_HISTORY_SIZE || window_epochs > current_epoch {
+            return None
+        }
+
+        let mut epoch = current_epoch - window_epochs;
+        let oldest_epoch = if current_epoch >= RATE_HISTORY_SIZE {
+            current_epoch - RATE_HISTORY_SIZE + 1
+        } else {
+            0
+        };
+        loop {
+            let snapshot_mapper = self.rate_snapshot(epoch % RATE_HISTORY_SIZE);
+            if !snapshot_mapper.is_empty() {
+                let snapshot = snapshot_mapper.get();
+                if snapshot.epoch == epoch {
+                    return Some(snapshot)
+                }
+            }
+            if epoch == oldest_epoch {
+                return None
+            }
+
+            epoch -= 1;
+        }
+    }
+
+    // yearly growth of the price since the snapshot, 0 if it didn't grow
+    fn get_apr(&self, old_price: &BigUint, new_price: &BigUint, old_epoch: u64) -> u64 {
+        let elapsed_epochs = self.blockchain().get_block_epoch() - old_epoch;
+        if new_price <= old_price || *old_price == 0 || elapsed_epochs == 0 {
+            return 0
+        }
+
+        let apr = (new_price - old_price) * MAX_PERCENT * EPOCHS_IN_YEAR / (old_price * elapsed_epochs);
+        apr.to_u64().unwrap_or(u64::MAX)
+    }
+
+    #[view(getRateSnapshot)]
+    fn get_rate_snapshot(&self, epoch: u64) -> OptionalValue<RateSnapshot<Self::Api>> {
+        let snapshot_mapper = self.rate_snapshot(epoch % RATE_HISTORY_SIZE);
+        if snapshot_mapper.is_empty() {
+            return OptionalValue::None
+        }
+
+        let snapshot = snapshot_mapper.get();
+        if snapshot.epoch == epoch {
+            OptionalValue::Some(snapshot)
+        } else {
+            OptionalValue::None
+        }
+    }
+
+    // liquid token APR over the last window_epochs (e.g. 7, 30 or 365)
+    #[view(getApr)]
+    fn get_liquid_apr(&self, window_epochs: u64) -> u64 {
+        match self.get_rate_snapshot_before(window_epochs) {
+            Some(snapshot) => self.get_apr(&snapshot.token_price, &self.token_price(), snapshot.epoch),
+            None => 0,
+        }
+    }
+
+    // reserve APR from the unDelegateNow fees, over the last window_epochs
+    #[view(getReserveApr)]
+    fn get_reserve_apr(&self, window_epochs: u64) -> u64 {
+        match self.get_rate_snapshot_before(window_epochs) {
+            Some(snapshot) => self.get_apr(&snapshot.reserve_price, &self.reserve_price(), snapshot.epoch),
+            None => 0,
+        }
+    }
+
+    #[storage_mapper("rate_snapshot")]
+    fn rate_snapshot(&self, slot: u64) -> SingleValueMapper<RateSnapshot<Self::Api>>;
 
     // misc
 
+    #[view(getReservePrice)]
+    fn reserve_price(&self) -> BigUint {
+        self.get_reserve_egld_amount(&BigUint::from(1_000_000_000_000_000_000u64))
+    }
+
     #[view(getTokenPrice)]
     fn token_price(&self) -> BigUint {
         let staked_egld = self.total_egld_staked().get();
         let token_supply = self.liquid_token_supply().get();
 
+        // same virtual shares and assets as the liquidity math
         let one = BigUint::from(1_000_000_000_000_000_000u64);
-        if (token_supply == 0) || (staked_egld == 0) {
-            one
-        } else {
-            one * staked_egld / token_supply
-        }
+        one * (staked_egld + VIRTUAL_ASSETS) / (token_supply + VIRTUAL_SHARES)
     }
 }
@@ -4,3 +4,11 @@ pub const MIN_GAS_FOR_CALLBACK: u64 = 12_000_000;
 pub const MAX_PERCENT: u64 = 10_000;
 pub const MAX_UNBOND_PERIOD: u64 = 20;
 pub const DUST_THRESHOLD: u64 = 1_000;
+pub const MAX_VESTING_EPOCHS: u64 = 30;
+pub const MAX_LOCK_MULTIPLIER: u64 = 30_000;
+pub const BOOST_INDEX_PRECISION: u64 = 1_000_000_000_000_000_000;
+pub const VIRTUAL_SHARES: u64 = 1_000;
+pub const VIRTUAL_ASSETS: u64 = 1_000;
+pub const EPOCHS_IN_YEAR: u64 = 365;
+pub const RATE_HISTORY_SIZE: u64 = 366;
+pub const LOSS_INDEX_PRECISION: u64 = 1_000_000_000_000_000_000;
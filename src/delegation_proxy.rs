@@ -12,12 +12,13 @@ pub trait DelegationProxy {
     #[endpoint(withdraw)]
     fn withdraw(&self);
 
-    #[endpoint(reDelegateRewards)]
-    fn redelegate_rewards(&self);
-
-    #[endpoint(getClaimableRewards)]
-    fn get_claimable_rewards(&self, address: ManagedAddress);
+    #[endpoint(claimRewards)]
+    fn claim_rewards(&self);
 
     #[endpoint(getUserActiveStake)]
     fn get_user_active_stake(&self, address: ManagedAddress);
+
+    // active stake, unclaimed rewards, unstaked (including unbondable) and unbondable funds
+    #[endpoint(getDelegatorFundsData)]
+    fn get_delegator_funds_data(&self, address: ManagedAddress);
 }
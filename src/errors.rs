@@ -2,19 +2,53 @@ pub static ERROR_INSUFFICIENT_AMOUNT: &[u8] = b"Insufficient amount";
 pub static ERROR_INSUFFICIENT_GAS: &[u8] = b"Insufficient gas remaining for the callback";
 pub static ERROR_NOT_ACTIVE: &[u8] = b"Not active";
 pub static ERROR_ACTIVE: &[u8] = b"Active state";
+pub static ERROR_DEPRECATED: &[u8] = b"Deprecated state";
 pub static ERROR_BAD_PAYMENT_TOKEN: &[u8] = b"Bad payment token";
 pub static ERROR_BAD_PAYMENT_AMOUNT: &[u8] = b"Insufficient undelegated amount";
 pub static ERROR_NOTHING_TO_WITHDRAW: &[u8] = b"Nothing to withdraw";
+pub static ERROR_NO_UNPAID_UNDELEGATIONS: &[u8] = b"No unpaid undelegations";
+pub static ERROR_UNBOND_PERIOD_NOT_ENDED: &[u8] = b"Unbond period not ended";
 pub static ERROR_NOT_ENOUGH_FUNDS: &[u8] = b"Not enough funds";
 pub static ERROR_USER_NOT_PROVIDER: &[u8] = b"The user is not a reserves provider";
 pub static ERROR_PROVIDER_ALREADY_SET: &[u8] = b"Provider address already set";
 pub static ERROR_PROVIDER_NOT_SET: &[u8] = b"Provider address not set";
+pub static ERROR_PROVIDER_CALLS_PENDING: &[u8] = b"Provider calls pending";
+pub static ERROR_NO_LEGACY_PROVIDER: &[u8] = b"No legacy provider";
+pub static ERROR_LEGACY_PROVIDER_NOT_IMPORTED: &[u8] = b"Legacy provider not imported";
+pub static ERROR_PROVIDER_NOT_FOUND: &[u8] = b"Provider not found";
+pub static ERROR_PROVIDER_NOT_EMPTY: &[u8] = b"Provider still has stake or pending undelegations";
+pub static ERROR_NO_PROVIDER_AVAILABLE: &[u8] = b"No provider available";
+pub static ERROR_INCORRECT_LOSS_SHARE: &[u8] = b"Loss share must not exceed 100%";
+pub static ERROR_INCORRECT_TOLERANCE: &[u8] = b"Tolerance must be less than 100%";
+pub static ERROR_INCORRECT_WEIGHT: &[u8] = b"Weight must be greater than 0";
+pub static ERROR_NOTHING_TO_COMPOUND: &[u8] = b"Nothing to compound";
+pub static ERROR_NOTHING_TO_CLAIM: &[u8] = b"Nothing to claim";
+pub static ERROR_MIGRATION_IN_PROGRESS: &[u8] = b"Provider migration in progress";
+pub static ERROR_NOTHING_TO_MIGRATE: &[u8] = b"Nothing to migrate";
+pub static ERROR_SAME_PROVIDER: &[u8] = b"Source and target providers must be different";
 pub static ERROR_UNBOND_PERIOD_NOT_SET: &[u8] = b"Unbond period not set";
 pub static ERROR_UNBOND_PERIOD_ALREADY_SET: &[u8] = b"Unbond period already set";
 pub static ERROR_TOKEN_ALREADY_SET: &[u8] = b"Token already set";
 pub static ERROR_TOKEN_NOT_SET: &[u8] = b"Token not set";
 pub static ERROR_NOT_ENOUGH_LIQUID_SUPPLY: &[u8] = b"Not enough liquid token supply";
 pub static ERROR_INCORRECT_FEE: &[u8] = b"Fee must be less than 100%";
+pub static ERROR_INCORRECT_KEEPER_SHARE: &[u8] = b"Keeper rewards share must be less than 100%";
+pub static ERROR_INCORRECT_PRICE_CHANGE: &[u8] = b"Price change must not be greater than 100%";
+pub static ERROR_NOT_ENOUGH_RATE_HISTORY: &[u8] = b"Not enough rate history";
+pub static ERROR_FEE_ABOVE_MAX: &[u8] = b"Fee above the order max fee";
+pub static ERROR_ORDER_NOT_FOUND: &[u8] = b"Order not found";
+pub static ERROR_NOT_ORDER_OWNER: &[u8] = b"Not the order owner";
+pub static ERROR_INCORRECT_LOCK_MULTIPLIER: &[u8] = b"Lock multiplier must be between 1x and 3x";
+pub static ERROR_INCORRECT_LOCK_EPOCHS: &[u8] = b"Lock epochs must be greater than 0";
+pub static ERROR_LOCK_TIER_NOT_FOUND: &[u8] = b"Lock tier not found";
+pub static ERROR_DIFFERENT_LOCK_TIER: &[u8] = b"Reserve already locked in a different tier";
+pub static ERROR_NO_LOCKED_RESERVE: &[u8] = b"No locked reserve";
+pub static ERROR_RESERVE_LOCKED: &[u8] = b"Reserve still locked";
+pub static ERROR_RESERVE_EXIT_NOT_FOUND: &[u8] = b"Reserve exit not found";
+pub static ERROR_NOT_RESERVE_EXIT_OWNER: &[u8] = b"Not the reserve exit owner";
+pub static ERROR_RESERVE_EXIT_TOO_SMALL: &[u8] = b"Queued reserve exit below the minimum";
+pub static ERROR_INCORRECT_VESTING_EPOCHS: &[u8] = b"Vesting epochs must be between 1 and 30";
+pub static ERROR_VESTING_EPOCHS_NOT_SET: &[u8] = b"Reserve fees vesting epochs not set";
+pub static ERROR_INCORRECT_MAX_FEE: &[u8] = b"Max fee must not be lower than the base fee";
 pub static ERROR_DUST_REMAINING: &[u8] = b"Can't leave dust";
-pub static ERROR_REMOVE_RESERVE_TOO_SOON: &[u8] = b"You can remove reserve only 1 epoch after add";
 pub static ERROR_FEE_CHANGED: &[u8] = b"Fee changed and you would receive less";
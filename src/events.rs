@@ -0,0 +1,505 @@
+multiversx_sc::imports!();
+
+use crate::config::State;
+
+#[multiversx_sc::module]
+pub trait EventsModule {
+    // liquid delegation
+
+    #[event("delegate")]
+    fn delegate_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] ls_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("unDelegate")]
+    fn undelegate_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] ls_amount: &BigUint,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] unbond_epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("withdraw")]
+    fn withdraw_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    // reserves
+
+    #[event("migrateUndelegations")]
+    fn migrate_undelegations_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] tokens_count: usize,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("addReserve")]
+    fn add_reserve_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] reserve_points: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("removeReserve")]
+    fn remove_reserve_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] reserve_points: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("unlockReserve")]
+    fn unlock_reserve_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] reserve_points: &BigUint,
+        #[indexed] boost_rewards: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("reserveExit")]
+    fn reserve_exit_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] exit_id: u64,
+        #[indexed] reserve_points: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("cancelReserveExit")]
+    fn cancel_reserve_exit_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] exit_id: u64,
+        #[indexed] reserve_points: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("fillReserveExit")]
+    fn fill_reserve_exit_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] owner: &ManagedAddress,
+        #[indexed] exit_id: u64,
+        #[indexed] reserve_points: &BigUint,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("claimReserveExit")]
+    fn claim_reserve_exit_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("migrateReserve")]
+    fn migrate_reserve_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] reserve_points: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("unDelegateNow")]
+    fn undelegate_now_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] ls_amount: &BigUint,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] fee: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("undelegationOrder")]
+    fn undelegation_order_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] order_id: u64,
+        #[indexed] ls_amount: &BigUint,
+        #[indexed] max_fee: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("cancelUndelegationOrder")]
+    fn cancel_undelegation_order_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] order_id: u64,
+        #[indexed] ls_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("fillUndelegationOrder")]
+    fn fill_undelegation_order_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] owner: &ManagedAddress,
+        #[indexed] order_id: u64,
+        #[indexed] ls_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    // service
+
+    #[event("delegateAll")]
+    fn delegate_all_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("delegateAllFailed")]
+    fn delegate_all_failed_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("unDelegateAll")]
+    fn undelegate_all_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] unbond_epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("unDelegateAllFailed")]
+    fn undelegate_all_failed_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("compound")]
+    fn compound_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] rewards: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("withdrawAll")]
+    fn withdraw_all_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("computeWithdrawn")]
+    fn compute_withdrawn_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] withdrawn_for_users: &BigUint,
+        #[indexed] withdrawn_for_reserves: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("reconcile")]
+    fn reconcile_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] tracked_stake: &BigUint,
+        #[indexed] active_stake: &BigUint,
+        #[indexed] tracked_undelegated: &BigUint,
+        #[indexed] unstaked: &BigUint,
+        #[indexed] paused: bool,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("unpaidUndelegations")]
+    fn unpaid_undelegations_event(
+        &self,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("loss")]
+    fn loss_event(
+        &self,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] loss: &BigUint,
+        #[indexed] reserve_part: &BigUint,
+        #[indexed] holders_part: &BigUint,
+        #[indexed] undelegations_part: &BigUint,
+        #[indexed] uncovered_part: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("keeperReward")]
+    fn keeper_reward_event(
+        &self,
+        #[indexed] keeper: &ManagedAddress,
+        #[indexed] reward: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("claimKeeperRewards")]
+    fn claim_keeper_rewards_event(
+        &self,
+        #[indexed] keeper: &ManagedAddress,
+        #[indexed] amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("priceMovePause")]
+    fn price_move_pause_event(
+        &self,
+        #[indexed] start_price: &BigUint,
+        #[indexed] token_price: &BigUint,
+        #[indexed] epoch: u64,
+    );
+
+    // config
+
+    #[event("setState")]
+    fn set_state_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] state: State,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setProvider")]
+    fn set_provider_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] weight: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("removeProvider")]
+    fn remove_provider_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("importLegacyProvider")]
+    fn import_legacy_provider_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] weight: u64,
+        #[indexed] egld_staked: &BigUint,
+        #[indexed] egld_undelegated: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("startProviderMigration")]
+    fn start_provider_migration_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] source: &ManagedAddress,
+        #[indexed] target: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("migrationUndelegate")]
+    fn migration_undelegate_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] unbond_epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("migrationUndelegateFailed")]
+    fn migration_undelegate_failed_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("delegateMigratedEgld")]
+    fn delegate_migrated_egld_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("delegateMigratedEgldFailed")]
+    fn delegate_migrated_egld_failed_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] provider: &ManagedAddress,
+        #[indexed] egld_amount: &BigUint,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setUnbondPeriod")]
+    fn set_unbond_period_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] unbond_period: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setUndelegateNowFee")]
+    fn set_undelegate_now_fee_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] fee: u64,
+        #[indexed] max_fee: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setProtocolFee")]
+    fn set_protocol_fee_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] fee: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setKeeperRewards")]
+    fn set_keeper_rewards_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] reward: &BigUint,
+        #[indexed] max_epoch_rewards: &BigUint,
+        #[indexed] rewards_share: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setReserveFeesVestingEpochs")]
+    fn set_reserve_fees_vesting_epochs_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] epochs: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setReserveLockTier")]
+    fn set_reserve_lock_tier_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] lock_epochs: u64,
+        #[indexed] multiplier: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setReconcileTolerance")]
+    fn set_reconcile_tolerance_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] tolerance: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setReserveLossShare")]
+    fn set_reserve_loss_share_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] share: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("removeReserveLockTier")]
+    fn remove_reserve_lock_tier_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] lock_epochs: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setMaxPriceChange")]
+    fn set_max_price_change_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] max_price_change: u64,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+
+    #[event("setTreasuryAddress")]
+    fn set_treasury_address_event(
+        &self,
+        #[indexed] caller: &ManagedAddress,
+        #[indexed] treasury: &ManagedAddress,
+        #[indexed] epoch: u64,
+        #[indexed] token_price: &BigUint,
+    );
+}
@@ -6,12 +6,14 @@ pub mod config;
 pub mod consts;
 pub mod delegation_proxy;
 pub mod errors;
+pub mod events;
 
 use crate::{config::*, consts::*, errors::*};
 
 #[multiversx_sc::contract]
 pub trait SalsaContract<ContractReader>:
     config::ConfigModule
+    + events::EventsModule
     + multiversx_sc_modules::default_issue_callbacks::DefaultIssueCallbacksModule
 {
     #[init]
@@ -25,6 +27,7 @@ pub trait SalsaContract<ContractReader>:
     #[endpoint(delegate)]
     fn delegate(&self) -> EsdtTokenPayment<Self::Api> {
         require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
 
         let delegate_amount = self.call_value().egld_value();
         require!(
@@ -33,59 +36,34 @@ pub trait SalsaContract<ContractReader>:
         );
 
         let ls_amount = self.add_liquidity(&delegate_amount);
+        self.egld_to_delegate()
+            .update(|value| *value += delegate_amount.clone_value());
+        self.net_pending_egld();
 
         let caller = self.blockchain().get_caller();
-        // check if caller is non-payable SC
-        if self.blockchain().is_smart_contract(&caller) {
-            self.send().direct_egld(&caller, &BigUint::zero());
-        }
-
-        let delegation_contract = self.provider_address().get();
-        let gas_for_async_call = self.get_gas_for_async_call();
-        self.delegation_proxy_obj()
-            .contract(delegation_contract)
-            .delegate()
-            .with_gas_limit(gas_for_async_call)
-            .with_egld_transfer(delegate_amount.clone_value())
-            .async_call()
-            .with_callback(
-                SalsaContract::callbacks(self).delegate_callback(caller, delegate_amount.clone_value(), ls_amount),
-            )
-            .call_and_exit()
-    }
+        let user_payment = self.mint_liquid_token(ls_amount);
+        self.send().direct_esdt(
+            &caller,
+            &user_payment.token_identifier,
+            user_payment.token_nonce,
+            &user_payment.amount,
+        );
+        self.delegate_event(
+            &caller,
+            &delegate_amount,
+            &user_payment.amount,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
 
-    #[callback]
-    fn delegate_callback(
-        &self,
-        caller: ManagedAddress,
-        staked_tokens: BigUint,
-        liquid_tokens: BigUint,
-        #[call_result] result: ManagedAsyncCallResult<()>,
-    ) {
-        match result {
-            ManagedAsyncCallResult::Ok(()) => {
-                let user_payment = self.mint_liquid_token(liquid_tokens);
-                self.send().direct_esdt(
-                    &caller,
-                    &user_payment.token_identifier,
-                    user_payment.token_nonce,
-                    &user_payment.amount,
-                );
-            }
-            ManagedAsyncCallResult::Err(_) => {
-                self.total_egld_staked()
-                    .update(|value| *value -= &staked_tokens);
-                self.liquid_token_supply()
-                    .update(|value| *value -= liquid_tokens);
-                self.send().direct_egld(&caller, &staked_tokens);
-            }
-        }
+        user_payment
     }
 
     #[payable("*")]
     #[endpoint(unDelegate)]
-    fn undelegate(&self) {
-        require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+    fn undelegate(&self) -> ManagedVec<EsdtTokenPayment<Self::Api>> {
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
 
         let payment = self.call_value().single_esdt();
         let liquid_token_id = self.liquid_token_id().get_token_id();
@@ -97,47 +75,150 @@ pub trait SalsaContract<ContractReader>:
 
         let egld_to_undelegate = self.remove_liquidity(&payment.amount);
         self.burn_liquid_token(&payment.amount);
-        self.egld_to_undelegate()
-            .update(|value| *value += &egld_to_undelegate);
+        let caller = self.blockchain().get_caller();
+        let undelegation_payments = self.add_user_undelegations(&caller, &egld_to_undelegate);
+        self.send().direct_multi(&caller, &undelegation_payments);
+        let current_epoch = self.blockchain().get_block_epoch();
+        self.undelegate_event(
+            &caller,
+            &payment.amount,
+            &egld_to_undelegate,
+            current_epoch,
+            current_epoch + self.unbond_period().get(),
+            &self.token_price(),
+        );
+
+        undelegation_payments
+    }
+
+    // the part covered by pending delegations doesn't need to wait for the unbond period
+    fn add_user_undelegations(
+        &self,
+        user: &ManagedAddress,
+        egld_to_undelegate: &BigUint,
+    ) -> ManagedVec<EsdtTokenPayment<Self::Api>> {
         let current_epoch = self.blockchain().get_block_epoch();
-        let unbond_period = current_epoch + self.unbond_period().get();
-        self.add_user_undelegation(egld_to_undelegate, unbond_period);
+        let unbond_epoch = current_epoch + self.unbond_period().get();
+        let mut undelegation_payments = ManagedVec::new();
+        let covered_amount = self.get_delegation_covered_amount(egld_to_undelegate);
+        if covered_amount > 0 {
+            undelegation_payments.push(self.add_user_undelegation(user, covered_amount.clone(), current_epoch));
+        }
+        if egld_to_undelegate > &covered_amount {
+            undelegation_payments.push(
+                self.add_user_undelegation(user, egld_to_undelegate - &covered_amount, unbond_epoch)
+            );
+        }
+        self.egld_to_undelegate()
+            .update(|value| *value += egld_to_undelegate);
+        self.net_pending_egld();
+
+        undelegation_payments
     }
 
+    #[payable("*")]
     #[endpoint(withdraw)]
     fn withdraw(&self) {
-        require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
+
+        let payments = self.call_value().all_esdt_transfers();
+        require!(!payments.is_empty(), ERROR_BAD_PAYMENT_TOKEN);
 
         let user = self.blockchain().get_caller();
-        self.compute_withdrawn();
+        self.compute_withdrawn_amounts();
         let current_epoch = self.blockchain().get_block_epoch();
+        let undelegation_token = self.undelegation_token_id();
         let mut total_user_withdrawn_egld = self.user_withdrawn_egld().get();
+        let mut withdraw_amount = BigUint::zero();
+        let mut remaining_payments = ManagedVec::new();
+        for payment in payments.iter() {
+            require!(
+                payment.token_identifier == undelegation_token.get_token_id(),
+                ERROR_BAD_PAYMENT_TOKEN
+            );
 
-        (total_user_withdrawn_egld, _) = self.remove_undelegations(
-            total_user_withdrawn_egld,
-            current_epoch,
-            self.luser_undelegations(&user),
-            UndelegationType::UserList,
-            user.clone()
-        );
-        let withdraw_amount = self.user_withdrawn_egld().get() - &total_user_withdrawn_egld;
+            let undelegation: Undelegation<Self::Api> =
+                undelegation_token.get_token_attributes(payment.token_nonce);
+            require!(undelegation.unbond_epoch <= current_epoch, ERROR_UNBOND_PERIOD_NOT_ENDED);
+
+            let undelegation_amount = self.get_undelegation_token_amount(payment.token_nonce, &undelegation);
+            undelegation_token.nft_burn(payment.token_nonce, &payment.amount);
+            self.undelegation_token_losses_count(payment.token_nonce).clear();
+            self.untrack_undelegation_token(payment.token_nonce);
+            let egld_amount = if undelegation_amount > total_user_withdrawn_egld {
+                total_user_withdrawn_egld.clone()
+            } else {
+                undelegation_amount.clone()
+            };
+            total_user_withdrawn_egld -= &egld_amount;
+            withdraw_amount += &egld_amount;
+
+            // the part not yet withdrawn from the providers is returned as a new token
+            if undelegation_amount > egld_amount {
+                remaining_payments.push(self.create_undelegation_token(
+                    &user,
+                    undelegation_amount - egld_amount,
+                    undelegation.unbond_epoch,
+                ));
+            }
+        }
         require!(withdraw_amount > 0, ERROR_NOTHING_TO_WITHDRAW);
 
         self.user_withdrawn_egld()
             .set(total_user_withdrawn_egld);
         self.send().direct_egld(&user, &withdraw_amount);
+        if !remaining_payments.is_empty() {
+            self.send().direct_multi(&user, &remaining_payments);
+        }
+        self.withdraw_event(&user, &withdraw_amount, current_epoch, &self.token_price());
+    }
+
+    #[endpoint(migrateUndelegations)]
+    fn migrate_undelegations(&self) -> ManagedVec<EsdtTokenPayment<Self::Api>> {
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+
+        let user = self.blockchain().get_caller();
+        let mut list = self.luser_undelegations(&user);
+        require!(!list.is_empty(), ERROR_NOTHING_TO_MIGRATE);
+
+        // the undelegations are already accounted in the total users list
+        let mut undelegation_payments = ManagedVec::new();
+        let mut total_amount = BigUint::zero();
+        while let Some(node) = list.pop_front() {
+            let undelegation = node.into_value();
+            total_amount += &undelegation.amount;
+            let payment = self.undelegation_token_id().nft_create(BigUint::from(1u64), &undelegation);
+            self.track_undelegation_token(&user, payment.token_nonce);
+            undelegation_payments.push(payment);
+        }
+        self.legacy_undelegation_users().swap_remove(&user);
+        // no index is stored, legacy undelegations are older than any loss
+        self.send().direct_multi(&user, &undelegation_payments);
+        self.migrate_undelegations_event(
+            &user,
+            &total_amount,
+            undelegation_payments.len(),
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+
+        undelegation_payments
     }
 
     // endpoints: reserves
 
+    // locked reserves get a bigger share of the fees and can't be removed until unlocked,
+    // their reserve tokens stay in the contract so nothing is returned
     #[payable("EGLD")]
     #[endpoint(addReserve)]
-    fn add_reserve(&self) {
+    fn add_reserve(&self, lock_epochs: OptionalValue<u64>) -> OptionalValue<EsdtTokenPayment<Self::Api>> {
         require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
+        self.vest_reserve_fees();
 
         let caller = self.blockchain().get_caller();
         let current_epoch = self.blockchain().get_block_epoch();
-        self.add_reserve_epoch(&caller).set(current_epoch);
 
         let reserve_amount = self.call_value().egld_value();
         require!(
@@ -147,36 +228,110 @@ pub trait SalsaContract<ContractReader>:
 
         let user_reserve_points = self.get_reserve_points_amount(&reserve_amount);
 
-        self.users_reserve_points(&caller)
-            .update(|value| *value += &user_reserve_points);
         self.reserve_points()
-            .update(|value| *value += user_reserve_points);
+            .update(|value| *value += &user_reserve_points);
 
         self.egld_reserve().update(|value| *value += reserve_amount.clone_value());
         self.available_egld_reserve().update(|value| *value += reserve_amount.clone_value());
+        self.add_reserve_event(
+            &caller,
+            &reserve_amount,
+            &user_reserve_points,
+            current_epoch,
+            &self.token_price(),
+        );
+
+        self.track_reserve_points(&caller, &user_reserve_points);
+        let user_payment = self.reserve_token_id().mint(user_reserve_points);
+        if let OptionalValue::Some(lock_epochs) = lock_epochs {
+            self.lock_reserve_points(&caller, &user_payment.amount, lock_epochs);
+            return OptionalValue::None
+        }
+
+        self.send().direct_esdt(
+            &caller,
+            &user_payment.token_identifier,
+            user_payment.token_nonce,
+            &user_payment.amount,
+        );
+
+        OptionalValue::Some(user_payment)
     }
 
-    #[endpoint(removeReserve)]
-    fn remove_reserve(&self, amount: BigUint) {
-        require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+    // the boost rewards are added to the reserve as new points
+    #[endpoint(unlockReserve)]
+    fn unlock_reserve(&self) -> EsdtTokenPayment<Self::Api> {
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
+        self.vest_reserve_fees();
 
         let caller = self.blockchain().get_caller();
+        require!(!self.locked_reserve(&caller).is_empty(), ERROR_NO_LOCKED_RESERVE);
+
+        let position = self.get_updated_locked_reserve(&caller);
         let current_epoch = self.blockchain().get_block_epoch();
-        let add_reserve_epoch = self.add_reserve_epoch(&caller).get();
+        require!(current_epoch >= position.unlock_epoch, ERROR_RESERVE_LOCKED);
+
+        self.locked_reserve(&caller).clear();
+        self.locked_reserve_users().swap_remove(&caller);
+        self.reserve_boost_weight()
+            .update(|value| *value -= self.get_boost_weight(&position.reserve_points, position.multiplier));
+        let mut reserve_points = position.reserve_points.clone();
+        if position.boost_rewards > 0 {
+            let boost_points = self.get_reserve_points_amount(&position.boost_rewards);
+            self.reserve_boost_pool()
+                .update(|value| *value -= &position.boost_rewards);
+            self.egld_reserve()
+                .update(|value| *value += &position.boost_rewards);
+            self.reserve_points()
+                .update(|value| *value += &boost_points);
+            self.reserve_token_id().mint(boost_points.clone());
+            self.track_reserve_points(&caller, &boost_points);
+            reserve_points += boost_points;
+        }
+        self.unlock_reserve_event(
+            &caller,
+            &reserve_points,
+            &position.boost_rewards,
+            current_epoch,
+            &self.token_price(),
+        );
+
+        let user_payment = EsdtTokenPayment::new(self.reserve_token_id().get_token_id(), 0, reserve_points);
+        self.send().direct_esdt(
+            &caller,
+            &user_payment.token_identifier,
+            user_payment.token_nonce,
+            &user_payment.amount,
+        );
+
+        user_payment
+    }
+
+    // the reserve tokens not needed to cover the amount are returned
+    #[payable("*")]
+    #[endpoint(removeReserve)]
+    fn remove_reserve(&self, amount: BigUint) {
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
+        self.vest_reserve_fees();
+
+        let payment = self.call_value().single_esdt();
         require!(
-            add_reserve_epoch < current_epoch,
-            ERROR_REMOVE_RESERVE_TOO_SOON
+            payment.token_identifier == self.reserve_token_id().get_token_id(),
+            ERROR_BAD_PAYMENT_TOKEN
         );
 
-        if add_reserve_epoch > 0 {
-            self.add_reserve_epoch(&caller).clear();
-        }
-        let old_reserve_points = self.users_reserve_points(&caller).get();
+        // the reserve token is transferable, so there is no add epoch per address to wait for,
+        // the reserve fees vesting keeps short term providers from taking the fees
+        let caller = self.blockchain().get_caller();
+        let current_epoch = self.blockchain().get_block_epoch();
+        let old_reserve_points = payment.amount;
         let old_reserve = self.get_reserve_egld_amount(&old_reserve_points);
         require!(old_reserve > 0, ERROR_USER_NOT_PROVIDER);
         require!(old_reserve >= amount, ERROR_NOT_ENOUGH_FUNDS);
 
-        self.compute_withdrawn();
+        self.compute_withdrawn_amounts();
 
         let mut egld_to_remove = amount.clone();
         let mut points_to_remove = self.get_reserve_points_amount(&egld_to_remove) + 1u64;
@@ -188,38 +343,195 @@ pub trait SalsaContract<ContractReader>:
             require!(&old_reserve - &amount >= MIN_EGLD, ERROR_DUST_REMAINING);
         }
 
-        self.egld_reserve().update(|value| *value -= &egld_to_remove);
-
-        let available_egld_reserve = self.available_egld_reserve().get();
-        // if there is not enough available reserve, move the reserve to user undelegation
+        // the queued exits are paid first, the rest of the amount waits in the queue
+        let available_egld_reserve = if self.reserve_exits().is_empty() {
+            self.available_egld_reserve().get()
+        } else {
+            BigUint::zero()
+        };
+        let mut queued_points = BigUint::zero();
         if egld_to_remove > available_egld_reserve {
-            let unbond_period = self.unbond_period().get();
-            let egld_to_move = &egld_to_remove - &available_egld_reserve;
-            let (remaining_egld, unbond_epoch) = self.remove_undelegations(
-                egld_to_move.clone(),
-                &current_epoch + &unbond_period,
-                self.lreserve_undelegations(),
-                UndelegationType::ReservesList,
-                caller.clone()
+            // small exits would only bloat the queue
+            require!(
+                &egld_to_remove - &available_egld_reserve >= MIN_EGLD,
+                ERROR_RESERVE_EXIT_TOO_SMALL
             );
-            require!(remaining_egld == 0, ERROR_NOT_ENOUGH_FUNDS);
-
-            self.add_user_undelegation(egld_to_move, unbond_epoch);
+            queued_points = &points_to_remove * &(&egld_to_remove - &available_egld_reserve) / &egld_to_remove;
+            points_to_remove -= &queued_points;
             egld_to_remove = available_egld_reserve;
+            self.queue_reserve_exit(&caller, &queued_points);
+        }
+        if old_reserve_points > &points_to_remove + &queued_points {
+            self.send().direct_esdt(
+                &caller,
+                &payment.token_identifier,
+                0,
+                &(&old_reserve_points - &points_to_remove - &queued_points),
+            );
+        }
+        if egld_to_remove == 0 {
+            return
         }
+
+        self.egld_reserve().update(|value| *value -= &egld_to_remove);
         self.available_egld_reserve()
             .update(|value| *value -= &egld_to_remove);
-        self.users_reserve_points(&caller)
-            .update(|value| *value -= &points_to_remove);
         self.reserve_points()
             .update(|value| *value -= &points_to_remove);
+        self.reserve_token_id().burn(&points_to_remove);
+        self.untrack_reserve_points(&caller, &points_to_remove);
         self.send().direct_egld(&caller, &egld_to_remove);
+        self.remove_reserve_event(
+            &caller,
+            &egld_to_remove,
+            &points_to_remove,
+            current_epoch,
+            &self.token_price(),
+        );
+    }
+
+    // anyone can fill the queued reserve exits once the reserve is available,
+    // at most max_exits per call so a long queue can be processed in several calls
+    #[endpoint(processReserveExits)]
+    fn process_reserve_exits(&self, max_exits: usize) {
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
+        self.vest_reserve_fees();
+        // a single keeper reward for computing the withdrawn amounts and filling the exits
+        let mut has_processed = self.compute_withdrawn_amounts();
+
+        let caller = self.blockchain().get_caller();
+        let current_epoch = self.blockchain().get_block_epoch();
+        let mut exits_count = 0;
+        while let Some(node) = self.reserve_exits().front() {
+            if exits_count == max_exits {
+                break
+            }
+
+            exits_count += 1;
+            let available_egld_reserve = self.available_egld_reserve().get();
+            if available_egld_reserve == 0 {
+                break
+            }
+
+            let mut exit = node.get_value_cloned();
+            let mut points_to_remove = exit.reserve_points.clone();
+            let mut egld_to_remove = self.get_reserve_egld_amount(&points_to_remove);
+            if egld_to_remove > available_egld_reserve {
+                points_to_remove = &points_to_remove * &available_egld_reserve / &egld_to_remove;
+                egld_to_remove = available_egld_reserve;
+            }
+            if points_to_remove == 0 {
+                break
+            }
+
+            self.egld_reserve().update(|value| *value -= &egld_to_remove);
+            self.available_egld_reserve()
+                .update(|value| *value -= &egld_to_remove);
+            self.reserve_points()
+                .update(|value| *value -= &points_to_remove);
+            self.queued_reserve_points()
+                .update(|value| *value -= &points_to_remove);
+            self.user_queued_reserve_points(&exit.owner)
+                .update(|value| *value -= &points_to_remove);
+            self.reserve_token_id().burn(&points_to_remove);
+            self.untrack_reserve_points(&exit.owner, &points_to_remove);
+            self.reserve_exit_claimable(&exit.owner)
+                .update(|value| *value += &egld_to_remove);
+            self.fill_reserve_exit_event(
+                &caller,
+                &exit.owner,
+                exit.id,
+                &points_to_remove,
+                &egld_to_remove,
+                current_epoch,
+                &self.token_price(),
+            );
+            has_processed = true;
+
+            if points_to_remove < exit.reserve_points {
+                exit.reserve_points -= &points_to_remove;
+                self.reserve_exits().set_node_value(node, exit);
+                break
+            }
+
+            self.reserve_exits().remove_node(&node);
+            self.reserve_exit_node_id(exit.id).clear();
+        }
+        if has_processed {
+            self.credit_keeper_reward(&caller);
+        }
+    }
+
+    // the queued reserve points are returned and stay in the reserve
+    #[endpoint(cancelReserveExit)]
+    fn cancel_reserve_exit(&self, exit_id: u64) {
+        let node = self.reserve_exits()
+            .get_node_by_id(self.reserve_exit_node_id(exit_id).get());
+        require!(node.is_some(), ERROR_RESERVE_EXIT_NOT_FOUND);
+
+        let node = node.unwrap();
+        let exit = node.get_value_cloned();
+        let caller = self.blockchain().get_caller();
+        require!(exit.owner == caller, ERROR_NOT_RESERVE_EXIT_OWNER);
+
+        self.reserve_exits().remove_node(&node);
+        self.reserve_exit_node_id(exit_id).clear();
+        self.queued_reserve_points()
+            .update(|value| *value -= &exit.reserve_points);
+        self.user_queued_reserve_points(&caller)
+            .update(|value| *value -= &exit.reserve_points);
+        self.send().direct_esdt(&caller, &self.reserve_token_id().get_token_id(), 0, &exit.reserve_points);
+        self.cancel_reserve_exit_event(
+            &caller,
+            exit_id,
+            &exit.reserve_points,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    #[endpoint(claimReserveExit)]
+    fn claim_reserve_exit(&self) {
+        let caller = self.blockchain().get_caller();
+        let egld_amount = self.reserve_exit_claimable(&caller).take();
+        require!(egld_amount > 0, ERROR_NOTHING_TO_CLAIM);
+
+        self.send().direct_egld(&caller, &egld_amount);
+        self.claim_reserve_exit_event(
+            &caller,
+            &egld_amount,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    #[endpoint(migrateReserve)]
+    fn migrate_reserve(&self) -> EsdtTokenPayment<Self::Api> {
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+
+        let caller = self.blockchain().get_caller();
+        let user_reserve_points = self.users_reserve_points(&caller).take();
+        require!(user_reserve_points > 0, ERROR_USER_NOT_PROVIDER);
+        self.legacy_reserve_users().swap_remove(&caller);
+
+        // the points are already accounted in the total reserve points
+        self.track_reserve_points(&caller, &user_reserve_points);
+        self.migrate_reserve_event(
+            &caller,
+            &user_reserve_points,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+        self.reserve_token_id()
+            .mint_and_send(&caller, user_reserve_points)
     }
 
     #[payable("*")]
     #[endpoint(unDelegateNow)]
-    fn undelegate_now(&self, min_amount_out: BigUint) {
+    fn undelegate_now(&self, min_amount_out: BigUint, allow_partial: OptionalValue<bool>) {
         require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
 
         let payment = self.call_value().single_esdt();
         let liquid_token_id = self.liquid_token_id().get_token_id();
@@ -229,46 +541,115 @@ pub trait SalsaContract<ContractReader>:
         );
         require!(payment.amount > 0u64, ERROR_BAD_PAYMENT_AMOUNT);
 
-        let fee = self.undelegate_now_fee().get();
-        let caller = self.blockchain().get_caller();
-        let total_egld_staked = self.total_egld_staked().get();
+        self.process_undelegate_now(
+            &self.blockchain().get_caller(),
+            &payment.amount,
+            &min_amount_out,
+            MAX_PERCENT,
+            allow_partial.into_option().unwrap_or_default(),
+        );
+    }
 
-        let egld_to_undelegate = self.remove_liquidity(&payment.amount);
-        self.burn_liquid_token(&payment.amount);
+    // the liquid tokens must already be in the contract
+    fn process_undelegate_now(
+        &self,
+        caller: &ManagedAddress,
+        ls_amount: &BigUint,
+        min_amount_out: &BigUint,
+        max_fee: u64,
+        allow_partial: bool,
+    ) {
+        let total_egld_staked = self.total_egld_staked().get();
+        let egld_to_undelegate = self.remove_liquidity(ls_amount);
+        self.burn_liquid_token(ls_amount);
         require!(
             egld_to_undelegate >= MIN_EGLD,
             ERROR_BAD_PAYMENT_AMOUNT
         );
 
+        require!(egld_to_undelegate <= total_egld_staked, ERROR_NOT_ENOUGH_FUNDS);
+
+        let mut fee = self.get_undelegate_now_fee_quote(&egld_to_undelegate);
         let available_egld_reserve = self.available_egld_reserve().get();
-        let egld_to_undelegate_with_fee =
+        let mut egld_to_undelegate_now = egld_to_undelegate.clone();
+        let mut egld_to_undelegate_with_fee =
             egld_to_undelegate.clone() - egld_to_undelegate.clone() * fee / MAX_PERCENT;
+        if egld_to_undelegate_with_fee > available_egld_reserve && allow_partial {
+            // only what the reserve covers is paid now, at the fee of a fully used reserve
+            fee = self.get_undelegate_now_fee_quote(&available_egld_reserve);
+            egld_to_undelegate_now = &available_egld_reserve * MAX_PERCENT / (MAX_PERCENT - fee);
+            if egld_to_undelegate_now > egld_to_undelegate {
+                egld_to_undelegate_now = egld_to_undelegate.clone();
+            }
+            egld_to_undelegate_with_fee =
+                egld_to_undelegate_now.clone() - egld_to_undelegate_now.clone() * fee / MAX_PERCENT;
+            if egld_to_undelegate_with_fee > available_egld_reserve {
+                egld_to_undelegate_with_fee = available_egld_reserve.clone();
+            }
+        }
         require!(
             egld_to_undelegate_with_fee <= available_egld_reserve,
             ERROR_NOT_ENOUGH_FUNDS
         );
-        require!(egld_to_undelegate <= total_egld_staked, ERROR_NOT_ENOUGH_FUNDS);
         require!(
-            egld_to_undelegate_with_fee >= min_amount_out,
+            &egld_to_undelegate_with_fee >= min_amount_out,
             ERROR_FEE_CHANGED
         );
+        require!(fee <= max_fee, ERROR_FEE_ABOVE_MAX);
 
-        // add to reserve undelegations
+        // the rest follows the unDelegate path
         let current_epoch = self.blockchain().get_block_epoch();
-        let unbond_epoch = current_epoch + self.unbond_period().get();
+        let egld_to_queue = &egld_to_undelegate - &egld_to_undelegate_now;
+        let ls_to_queue = ls_amount * &egld_to_queue / &egld_to_undelegate;
+        if egld_to_queue > 0 {
+            let undelegation_payments = self.add_user_undelegations(caller, &egld_to_queue);
+            self.send().direct_multi(caller, &undelegation_payments);
+            self.undelegate_event(
+                caller,
+                &ls_to_queue,
+                &egld_to_queue,
+                current_epoch,
+                current_epoch + self.unbond_period().get(),
+                &self.token_price(),
+            );
+        }
+        if egld_to_undelegate_now == 0 {
+            return
+        }
 
-        self.add_undelegation(egld_to_undelegate.clone(), unbond_epoch, self.lreserve_undelegations());
+        // add to reserve undelegations
+        let egld_to_undelegate = egld_to_undelegate_now;
+        let unbond_epoch = current_epoch + self.unbond_period().get();
+        let covered_amount = self.get_delegation_covered_amount(&egld_to_undelegate);
+        if covered_amount > 0 {
+            self.add_undelegation(covered_amount.clone(), current_epoch, self.lreserve_undelegations());
+        }
+        if egld_to_undelegate > covered_amount {
+            self.add_undelegation(
+                &egld_to_undelegate - &covered_amount,
+                unbond_epoch,
+                self.lreserve_undelegations(),
+            );
+        }
 
         // update storage
         self.egld_to_undelegate()
             .update(|value| *value += &egld_to_undelegate);
+        self.net_pending_egld();
         self.available_egld_reserve()
             .update(|value| *value -= &egld_to_undelegate_with_fee);
         let total_rewards = &egld_to_undelegate - &egld_to_undelegate_with_fee;
-        self.egld_reserve()
-            .update(|value| *value += &total_rewards);
-
-        self.send().direct_egld(&caller, &egld_to_undelegate_with_fee);
+        self.add_reserve_fees(&total_rewards);
+
+        self.send().direct_egld(caller, &egld_to_undelegate_with_fee);
+        self.undelegate_now_event(
+            caller,
+            &(ls_amount - &ls_to_queue),
+            &egld_to_undelegate_with_fee,
+            fee,
+            current_epoch,
+            &self.token_price(),
+        );
     }
 
     fn add_undelegation(
@@ -328,10 +709,37 @@ pub trait SalsaContract<ContractReader>:
         }
     }
 
-    fn add_user_undelegation(&self, amount: BigUint, unbond_epoch: u64) {
-        let user = self.blockchain().get_caller();
-        self.add_undelegation(amount.clone(), unbond_epoch, self.luser_undelegations(&user));
-        self.add_undelegation(amount, unbond_epoch, self.ltotal_user_undelegations());
+    fn add_user_undelegation(
+        &self,
+        user: &ManagedAddress,
+        amount: BigUint,
+        unbond_epoch: u64,
+    ) -> EsdtTokenPayment<Self::Api> {
+        self.add_undelegation(amount.clone(), unbond_epoch, self.ltotal_user_undelegations());
+        self.create_undelegation_token(user, amount, unbond_epoch)
+    }
+
+    fn create_undelegation_token(
+        &self,
+        user: &ManagedAddress,
+        amount: BigUint,
+        unbond_epoch: u64,
+    ) -> EsdtTokenPayment<Self::Api> {
+        let payment = self.undelegation_token_id().nft_create(
+            BigUint::from(1u64),
+            &Undelegation {
+                amount,
+                unbond_epoch,
+            },
+        );
+        let losses_count = self.undelegations_losses().len();
+        if losses_count > 0 {
+            self.undelegation_token_losses_count(payment.token_nonce)
+                .set(losses_count);
+        }
+        self.track_undelegation_token(user, payment.token_nonce);
+
+        payment
     }
 
     fn remove_undelegations(
@@ -346,7 +754,7 @@ pub trait SalsaContract<ContractReader>:
         let mut total_amount = amount;
         let current_epoch = self.blockchain().get_block_epoch();
         let unbond_period = self.unbond_period().get();
-        let mut last_epoch = &current_epoch + &unbond_period;
+        let mut last_epoch = current_epoch + unbond_period;
         for node in list.iter() {
             let mut modified = false;
             let node_id = node.get_node_id();
@@ -363,7 +771,7 @@ pub trait SalsaContract<ContractReader>:
                 }
             }
             if undelegation.amount == 0 {
-                clone_list.remove_node_by_id(node_id.clone());
+                clone_list.remove_node_by_id(node_id);
             } else if modified {
                 clone_list.set_node_value_by_id(node_id, undelegation);
             }
@@ -377,116 +785,320 @@ pub trait SalsaContract<ContractReader>:
         list_type: UndelegationType,
         user: &ManagedAddress
     ) -> LinkedListMapper<Undelegation<Self::Api>> {
-        if list_type == UndelegationType::UserList {
-            self.luser_undelegations(user)
-        } else if list_type == UndelegationType::TotalUsersList {
+        if list_type == UndelegationType::TotalUsersList {
             self.ltotal_user_undelegations()
+        } else if list_type == UndelegationType::ProviderList {
+            self.lprovider_undelegations(user)
+        } else if list_type == UndelegationType::MigrationList {
+            self.lmigration_undelegations()
         } else {
             self.lreserve_undelegations()
         }
     }
+
+    // endpoints: undelegation orders
+
+    // the liquid tokens are escrowed until the order is filled at a fee not above max_fee
+    #[payable("*")]
+    #[endpoint(placeUndelegationOrder)]
+    fn place_undelegation_order(&self, max_fee: u64) -> u64 {
+        require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        require!(max_fee < MAX_PERCENT, ERROR_INCORRECT_FEE);
+
+        let payment = self.call_value().single_esdt();
+        require!(
+            payment.token_identifier == self.liquid_token_id().get_token_id(),
+            ERROR_BAD_PAYMENT_TOKEN
+        );
+        // same minimum as the fill, otherwise the order can never be filled
+        let egld_amount = self.get_liquid_egld_amount(&payment.amount);
+        require!(egld_amount >= MIN_EGLD, ERROR_BAD_PAYMENT_AMOUNT);
+
+        let caller = self.blockchain().get_caller();
+        let order_id = self.last_undelegation_order_id().update(|id| {
+            *id += 1;
+            *id
+        });
+        let order = UndelegationOrder {
+            owner: caller.clone(),
+            ls_amount: payment.amount.clone(),
+            max_fee,
+        };
+        self.undelegation_order(order_id).set(&order);
+        self.undelegation_order_ids().insert(order_id);
+        self.undelegation_order_event(
+            &caller,
+            order_id,
+            &payment.amount,
+            max_fee,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+
+        order_id
+    }
+
+    #[endpoint(cancelUndelegationOrder)]
+    fn cancel_undelegation_order(&self, order_id: u64) {
+        let order = self.get_undelegation_order(order_id);
+        let caller = self.blockchain().get_caller();
+        require!(order.owner == caller, ERROR_NOT_ORDER_OWNER);
+
+        self.remove_undelegation_order(order_id);
+        self.send().direct_esdt(&caller, &self.liquid_token_id().get_token_id(), 0, &order.ls_amount);
+        self.cancel_undelegation_order_event(
+            &caller,
+            order_id,
+            &order.ls_amount,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    // anyone can fill an order once the reserve covers it within its max fee,
+    // fills aren't rewarded since they only move funds between users
+    #[endpoint(fillUndelegationOrder)]
+    fn fill_undelegation_order(&self, order_id: u64) {
+        require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
+
+        let order = self.get_undelegation_order(order_id);
+        self.remove_undelegation_order(order_id);
+        self.process_undelegate_now(
+            &order.owner,
+            &order.ls_amount,
+            &BigUint::zero(),
+            order.max_fee,
+            false,
+        );
+
+        let caller = self.blockchain().get_caller();
+        self.fill_undelegation_order_event(
+            &caller,
+            &order.owner,
+            order_id,
+            &order.ls_amount,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    fn get_undelegation_order(&self, order_id: u64) -> UndelegationOrder<Self::Api> {
+        require!(!self.undelegation_order(order_id).is_empty(), ERROR_ORDER_NOT_FOUND);
+
+        self.undelegation_order(order_id).get()
+    }
+
+    fn remove_undelegation_order(&self, order_id: u64) {
+        self.undelegation_order(order_id).clear();
+        self.undelegation_order_ids().swap_remove(&order_id);
+    }
+
     // endpoints: service
 
-    #[endpoint(unDelegateAll)]
-    fn undelegate_all(&self) {
+    #[endpoint(delegateAll)]
+    fn delegate_all(&self) {
         require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
 
-        let egld_to_undelegate = self.egld_to_undelegate().get();
+        self.net_pending_egld();
+        let egld_to_delegate = self.egld_to_delegate().get();
         require!(
-            egld_to_undelegate >= MIN_EGLD,
+            egld_to_delegate >= MIN_EGLD,
             ERROR_INSUFFICIENT_AMOUNT
         );
 
-        self.egld_to_undelegate().clear();
+        self.egld_to_delegate().clear();
+        let caller = self.blockchain().get_caller();
+        let delegation_contract = self.get_delegate_provider(&egld_to_delegate);
+        self.add_provider_stake(&delegation_contract, &egld_to_delegate);
 
-        let delegation_contract = self.provider_address().get();
+        self.start_provider_call(&delegation_contract);
         let gas_for_async_call = self.get_gas_for_async_call();
         self.delegation_proxy_obj()
-            .contract(delegation_contract)
-            .undelegate(egld_to_undelegate.clone())
+            .contract(delegation_contract.clone())
+            .delegate()
             .with_gas_limit(gas_for_async_call)
+            .with_egld_transfer(egld_to_delegate.clone())
             .async_call()
             .with_callback(
-                SalsaContract::callbacks(self).undelegate_all_callback(egld_to_undelegate),
+                SalsaContract::callbacks(self)
+                    .delegate_all_callback(caller, delegation_contract, egld_to_delegate),
             )
             .call_and_exit()
     }
 
     #[callback]
-    fn undelegate_all_callback(
+    fn delegate_all_callback(
         &self,
-        egld_to_undelegate: BigUint,
+        caller: ManagedAddress,
+        provider: ManagedAddress,
+        egld_to_delegate: BigUint,
         #[call_result] result: ManagedAsyncCallResult<()>,
     ) {
+        self.end_provider_call(&provider);
+        let current_epoch = self.blockchain().get_block_epoch();
         match result {
-            ManagedAsyncCallResult::Ok(()) => {}
+            ManagedAsyncCallResult::Ok(()) => {
+                self.delegate_all_event(
+                    &caller,
+                    &provider,
+                    &egld_to_delegate,
+                    current_epoch,
+                    &self.token_price(),
+                );
+            }
             ManagedAsyncCallResult::Err(_) => {
-                self.egld_to_undelegate()
-                    .update(|value| *value += egld_to_undelegate);
+                self.remove_provider_stake(&provider, &egld_to_delegate);
+                self.egld_to_delegate()
+                    .update(|value| *value += &egld_to_delegate);
+                self.delegate_all_failed_event(
+                    &caller,
+                    &provider,
+                    &egld_to_delegate,
+                    current_epoch,
+                    &self.token_price(),
+                );
             }
         }
     }
 
-    #[endpoint(compound)]
-    fn compound(&self) {
-        require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+    #[endpoint(unDelegateAll)]
+    fn undelegate_all(&self) {
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
 
-        let delegation_contract = self.provider_address().get();
-        let this_contract = self.blockchain().get_sc_address();
-        let gas_for_async_call = self.get_gas_for_async_call();
-        let claimable_rewards_amount = self.claimable_rewards_amount().get();
-        let claimable_rewards_epoch = self.claimable_rewards_epoch().get();
-        let current_epoch = self.blockchain().get_block_epoch();
+        self.net_pending_egld();
+        let egld_to_undelegate = self.egld_to_undelegate().get();
+        require!(
+            egld_to_undelegate >= MIN_EGLD,
+            ERROR_INSUFFICIENT_AMOUNT
+        );
 
-        if claimable_rewards_amount == 0 || claimable_rewards_epoch != current_epoch {
-            self.delegation_proxy_obj()
-                .contract(delegation_contract)
-                .get_claimable_rewards(this_contract)
-                .with_gas_limit(gas_for_async_call)
-                .async_call()
-                .with_callback(
-                    SalsaContract::callbacks(self).get_claimable_rewards_callback(current_epoch),
-                )
-                .call_and_exit()
-        } else {
-            self.delegation_proxy_obj()
-                .contract(delegation_contract)
-                .redelegate_rewards()
-                .with_gas_limit(gas_for_async_call)
-                .async_call()
-                .with_callback(
-                    SalsaContract::callbacks(self).compound_callback(claimable_rewards_amount),
-                )
-                .call_and_exit()
-        }
-    }
+        let caller = self.blockchain().get_caller();
+        let (delegation_contract, amount) = self.get_undelegate_provider(&egld_to_undelegate);
+        self.egld_to_undelegate()
+            .update(|value| *value -= &amount);
+        self.remove_provider_stake(&delegation_contract, &amount);
 
-    #[callback]
-    fn get_claimable_rewards_callback(
-        &self,
-        current_epoch: u64,
-        #[call_result] result: ManagedAsyncCallResult<BigUint>,
-    ) {
-        match result {
-            ManagedAsyncCallResult::Ok(total_rewards) => {
-                self.claimable_rewards_amount().set(total_rewards);
-                self.claimable_rewards_epoch().set(current_epoch);
+        self.start_provider_call(&delegation_contract);
+        let gas_for_async_call = self.get_gas_for_async_call();
+        self.delegation_proxy_obj()
+            .contract(delegation_contract.clone())
+            .undelegate(amount.clone())
+            .with_gas_limit(gas_for_async_call)
+            .async_call()
+            .with_callback(
+                SalsaContract::callbacks(self)
+                    .undelegate_all_callback(caller, delegation_contract, amount),
+            )
+            .call_and_exit()
+    }
+
+    #[callback]
+    fn undelegate_all_callback(
+        &self,
+        caller: ManagedAddress,
+        provider: ManagedAddress,
+        egld_to_undelegate: BigUint,
+        #[call_result] result: ManagedAsyncCallResult<()>,
+    ) {
+        self.end_provider_call(&provider);
+        match result {
+            ManagedAsyncCallResult::Ok(()) => {
+                let current_epoch = self.blockchain().get_block_epoch();
+                let unbond_epoch = current_epoch + self.unbond_period().get();
+                self.add_undelegation(
+                    egld_to_undelegate.clone(),
+                    unbond_epoch,
+                    self.lprovider_undelegations(&provider),
+                );
+                self.undelegate_all_event(
+                    &caller,
+                    &provider,
+                    &egld_to_undelegate,
+                    current_epoch,
+                    unbond_epoch,
+                    &self.token_price(),
+                );
+                // once per epoch, so undelegating just to trigger unDelegateAll isn't rewarded
+                if self.keeper_undelegate_all_epoch().get() != current_epoch {
+                    self.keeper_undelegate_all_epoch().set(current_epoch);
+                    self.credit_keeper_reward(&caller);
+                }
+            }
+            ManagedAsyncCallResult::Err(_) => {
+                self.add_provider_stake(&provider, &egld_to_undelegate);
+                self.egld_to_undelegate()
+                    .update(|value| *value += &egld_to_undelegate);
+                self.undelegate_all_failed_event(
+                    &caller,
+                    &provider,
+                    &egld_to_undelegate,
+                    self.blockchain().get_block_epoch(),
+                    &self.token_price(),
+                );
             }
-            ManagedAsyncCallResult::Err(_) => {}
         }
     }
 
+    #[endpoint(compound)]
+    fn compound(&self) {
+        require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
+
+        let caller = self.blockchain().get_caller();
+        let gas_for_async_call = self.get_gas_for_async_call();
+        let current_epoch = self.blockchain().get_block_epoch();
+
+        let delegation_contract = match self.find_compound_provider() {
+            Some(address) => address,
+            None => sc_panic!(ERROR_NOTHING_TO_COMPOUND),
+        };
+        self.delegation_proxy_obj()
+            .contract(delegation_contract.clone())
+            .claim_rewards()
+            .with_gas_limit(gas_for_async_call)
+            .async_call()
+            .with_callback(
+                SalsaContract::callbacks(self)
+                    .compound_callback(caller, delegation_contract, current_epoch),
+            )
+            .call_and_exit()
+    }
+
     #[callback]
     fn compound_callback(
         &self,
-        claimable_rewards: BigUint,
+        caller: ManagedAddress,
+        provider: ManagedAddress,
+        current_epoch: u64,
         #[call_result] result: ManagedAsyncCallResult<()>,
     ) {
         match result {
             ManagedAsyncCallResult::Ok(()) => {
-                self.total_egld_staked()
-                    .update(|value| *value += claimable_rewards);
-                self.claimable_rewards_amount().clear();
+                // the received rewards are delegated with the next delegateAll
+                let mut rewards = self.call_value().egld_value().clone_value();
+                self.claimable_rewards_epoch(&provider).set(current_epoch);
+                if rewards == 0 {
+                    return
+                }
+
+                let keeper_share = &rewards * self.keeper_rewards_share().get() / MAX_PERCENT;
+                self.keeper_rewards_pool()
+                    .update(|value| *value += &keeper_share);
+                rewards -= keeper_share;
+                self.egld_to_delegate()
+                    .update(|value| *value += &rewards);
+                self.add_rewards(&rewards);
+                self.compound_event(
+                    &caller,
+                    &provider,
+                    &rewards,
+                    current_epoch,
+                    &self.token_price(),
+                );
+                self.credit_keeper_reward(&caller);
             }
             ManagedAsyncCallResult::Err(_) => {}
         }
@@ -494,27 +1106,85 @@ pub trait SalsaContract<ContractReader>:
 
     #[endpoint(withdrawAll)]
     fn withdraw_all(&self) {
-        require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+        self.update_rate_history();
 
-        let delegation_contract = self.provider_address().get();
+        let caller = self.blockchain().get_caller();
+        let delegation_contract = self.get_withdraw_provider();
+        self.start_provider_call(&delegation_contract);
         let gas_for_async_call = self.get_gas_for_async_call();
 
         self.delegation_proxy_obj()
-            .contract(delegation_contract)
+            .contract(delegation_contract.clone())
             .withdraw()
             .with_gas_limit(gas_for_async_call)
             .async_call()
-            .with_callback(SalsaContract::callbacks(self).withdraw_all_callback())
+            .with_callback(
+                SalsaContract::callbacks(self).withdraw_all_callback(caller, delegation_contract),
+            )
             .call_and_exit()
     }
 
     #[callback]
-    fn withdraw_all_callback(&self, #[call_result] result: ManagedAsyncCallResult<()>) {
+    fn withdraw_all_callback(
+        &self,
+        caller: ManagedAddress,
+        provider: ManagedAddress,
+        #[call_result] result: ManagedAsyncCallResult<()>,
+    ) {
+        self.end_provider_call(&provider);
         match result {
             ManagedAsyncCallResult::Ok(()) => {
-                let withdrawn_amount = self.call_value().egld_value();
+                let withdrawn_amount = self.call_value().egld_value().clone_value();
+                let current_epoch = self.blockchain().get_block_epoch();
+                self.remove_undelegations(
+                    withdrawn_amount.clone(),
+                    current_epoch,
+                    self.lprovider_undelegations(&provider),
+                    UndelegationType::ProviderList,
+                    provider.clone(),
+                );
+                self.withdraw_all_event(
+                    &caller,
+                    &provider,
+                    &withdrawn_amount,
+                    current_epoch,
+                    &self.token_price(),
+                );
+                let is_migration_source =
+                    !self.migration_source().is_empty() && self.migration_source().get() == provider;
+                let has_withdrawn = withdrawn_amount > 0;
+                let withdrawn_for_undelegations = if is_migration_source {
+                    self.withdraw_migrated_egld(withdrawn_amount)
+                } else {
+                    withdrawn_amount
+                };
                 self.total_withdrawn_egld()
-                    .update(|value| *value += withdrawn_amount.clone_value());
+                    .update(|value| *value += withdrawn_for_undelegations);
+                if has_withdrawn {
+                    self.credit_keeper_reward(&caller);
+                }
+
+                // matured undelegations not returned by the provider are kept until the owner
+                // confirms the loss, the provider is retried in the next epoch
+                if !is_migration_source {
+                    let unpaid_amount = self.get_matured_undelegations_amount(
+                        self.lprovider_undelegations(&provider),
+                    );
+                    if unpaid_amount > 0 {
+                        self.unpaid_undelegations(&provider).set(&unpaid_amount);
+                        self.unpaid_undelegations_epoch(&provider).set(current_epoch);
+                        self.unpaid_undelegations_event(
+                            &provider,
+                            &unpaid_amount,
+                            current_epoch,
+                            &self.token_price(),
+                        );
+                    } else {
+                        self.unpaid_undelegations(&provider).clear();
+                        self.unpaid_undelegations_epoch(&provider).clear();
+                    }
+                }
             }
             ManagedAsyncCallResult::Err(_) => {}
         }
@@ -522,6 +1192,15 @@ pub trait SalsaContract<ContractReader>:
 
     #[endpoint(computeWithdrawn)]
     fn compute_withdrawn(&self) {
+        if self.compute_withdrawn_amounts() {
+            let caller = self.blockchain().get_caller();
+            self.credit_keeper_reward(&caller);
+        }
+    }
+
+    // returns true if any withdrawn amount was assigned
+    fn compute_withdrawn_amounts(&self) -> bool {
+        self.update_rate_history();
         let current_epoch = self.blockchain().get_block_epoch();
         let total_withdrawn_egld = self.total_withdrawn_egld().get();
         let caller = self.blockchain().get_caller();
@@ -544,18 +1223,660 @@ pub trait SalsaContract<ContractReader>:
             current_epoch,
             self.lreserve_undelegations(),
             UndelegationType::ReservesList,
-            caller
+            caller.clone()
         );
         let withdrawn_for_reserves = &total_withdrawn_egld - &left_amount - &withdrawn_for_users;
         self.available_egld_reserve()
-            .update(|value| *value += withdrawn_for_reserves);
+            .update(|value| *value += &withdrawn_for_reserves);
         
         self.total_withdrawn_egld()
             .set(&left_amount);
+        if withdrawn_for_users > 0 || withdrawn_for_reserves > 0 {
+            self.compute_withdrawn_event(
+                &caller,
+                &withdrawn_for_users,
+                &withdrawn_for_reserves,
+                current_epoch,
+                &self.token_price(),
+            );
+            return true
+        }
+
+        false
+    }
+
+    #[endpoint(claimKeeperRewards)]
+    fn claim_keeper_rewards(&self) {
+        let caller = self.blockchain().get_caller();
+        let rewards = self.keeper_claimable_rewards(&caller).take();
+        require!(rewards > 0, ERROR_NOTHING_TO_CLAIM);
+
+        self.send().direct_egld(&caller, &rewards);
+        self.claim_keeper_rewards_event(
+            &caller,
+            &rewards,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    #[endpoint(reconcile)]
+    fn reconcile(&self, provider: ManagedAddress) {
+        // the owner can reconcile a paused contract
+        let caller = self.blockchain().get_caller();
+        require!(
+            self.is_state_active() || caller == self.blockchain().get_owner_address(),
+            ERROR_NOT_ACTIVE
+        );
+
+        // the tracked amounts are only final when no other call to the provider is in flight
+        self.get_provider(&provider);
+        require!(
+            self.provider_pending_calls(&provider).get() == 0,
+            ERROR_PROVIDER_CALLS_PENDING
+        );
+
+        let calls_nonce = self.provider_calls_nonce(&provider).get();
+        let this_contract = self.blockchain().get_sc_address();
+        let gas_for_async_call = self.get_gas_for_async_call();
+        self.delegation_proxy_obj()
+            .contract(provider.clone())
+            .get_delegator_funds_data(this_contract)
+            .with_gas_limit(gas_for_async_call)
+            .async_call()
+            .with_callback(
+                SalsaContract::callbacks(self).reconcile_callback(caller, provider, calls_nonce),
+            )
+            .call_and_exit()
+    }
+
+    // the active stake is compared with the tracked stake and the unstaked funds with the
+    // provider undelegations. only the active stake is adjusted, undelegations not paid are
+    // handled by withdrawAll
+    #[callback]
+    fn reconcile_callback(
+        &self,
+        caller: ManagedAddress,
+        provider: ManagedAddress,
+        calls_nonce: u64,
+        #[call_result] result: ManagedAsyncCallResult<MultiValue4<BigUint, BigUint, BigUint, BigUint>>,
+    ) {
+        let (active_stake, _, unstaked, _) = match result {
+            ManagedAsyncCallResult::Ok(value) => value.into_tuple(),
+            ManagedAsyncCallResult::Err(_) => return,
+        };
+        let mut provider_config = match self.providers().get(&provider) {
+            Some(value) => value,
+            None => return,
+        };
+
+        // another call was sent meanwhile, the provider data may not match the tracked amounts
+        if self.provider_calls_nonce(&provider).get() != calls_nonce {
+            return
+        }
+
+        let tolerance = self.reconcile_tolerance().get();
+        let tracked_stake = provider_config.total_staked.clone();
+        let difference = if active_stake > tracked_stake {
+            &active_stake - &tracked_stake
+        } else {
+            &tracked_stake - &active_stake
+        };
+        let max_difference = &tracked_stake * tolerance / MAX_PERCENT;
+
+        // more unstaked funds than tracked are ignored, e.g. after a confirmed undelegation loss
+        let tracked_undelegated = self.get_undelegations_amount(self.lprovider_undelegations(&provider));
+        let undelegated_shortfall = if tracked_undelegated > unstaked {
+            &tracked_undelegated - &unstaked
+        } else {
+            BigUint::zero()
+        };
+        let max_undelegated_shortfall = &tracked_undelegated * tolerance / MAX_PERCENT;
+
+        let paused = difference > max_difference || undelegated_shortfall > max_undelegated_shortfall;
+        if paused {
+            // too big to be a rounding or a fee change, only allow exits until the owner investigates
+            if self.is_state_active() {
+                self.state().set(State::ExitOnly);
+            }
+        } else if difference > 0 {
+            if active_stake > tracked_stake {
+                self.add_rewards(&difference);
+            } else {
+                self.apply_stake_loss(&provider, &difference);
+            }
+            provider_config.total_staked = active_stake.clone();
+            self.providers().insert(provider.clone(), provider_config);
+        }
+        self.reconcile_event(
+            &caller,
+            &provider,
+            &tracked_stake,
+            &active_stake,
+            &tracked_undelegated,
+            &unstaked,
+            paused,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+    }
+
+    // the unpaid undelegations still not returned are written down as a loss
+    #[only_owner]
+    #[endpoint(confirmUndelegationLoss)]
+    fn confirm_undelegation_loss(&self, provider: ManagedAddress) {
+        let unpaid_amount = self.unpaid_undelegations(&provider).get();
+        let matured_amount = self.get_matured_undelegations_amount(self.lprovider_undelegations(&provider));
+        let loss = if unpaid_amount > matured_amount {
+            matured_amount
+        } else {
+            unpaid_amount
+        };
+        require!(loss > 0, ERROR_NO_UNPAID_UNDELEGATIONS);
+
+        self.unpaid_undelegations(&provider).clear();
+        self.unpaid_undelegations_epoch(&provider).clear();
+        self.remove_undelegations(
+            loss.clone(),
+            self.blockchain().get_block_epoch(),
+            self.lprovider_undelegations(&provider),
+            UndelegationType::ProviderList,
+            provider.clone(),
+        );
+        self.apply_undelegation_loss(&provider, &loss);
+    }
+
+    // endpoints: provider migration
+
+    // after the upgrade from the single provider version, the old provider is added to the
+    // registry with the stake and the undelegations still pending there
+    #[only_owner]
+    #[endpoint(importLegacyProvider)]
+    fn import_legacy_provider(&self, weight: u64) {
+        require!(!self.is_state_active(), ERROR_ACTIVE);
+        require!(!self.provider_address().is_empty(), ERROR_NO_LEGACY_PROVIDER);
+        require!(weight > 0, ERROR_INCORRECT_WEIGHT);
+
+        let provider = self.provider_address().take();
+        require!(
+            !self.providers().contains_key(&provider),
+            ERROR_PROVIDER_ALREADY_SET
+        );
+
+        // the undelegations not yet sent are still staked
+        let egld_to_undelegate = self.egld_to_undelegate().get();
+        let total_staked = self.total_egld_staked().get() + &egld_to_undelegate;
+        self.providers().insert(
+            provider.clone(),
+            ProviderConfig {
+                weight,
+                total_staked: total_staked.clone(),
+            },
+        );
+
+        // the provider owes the user and reserve undelegations, except the ones already withdrawn
+        // (the oldest) and the ones not yet sent (the newest)
+        for node in self.ltotal_user_undelegations().iter().chain(self.lreserve_undelegations().iter()) {
+            let undelegation = node.into_value();
+            self.add_undelegation(
+                undelegation.amount,
+                undelegation.unbond_epoch,
+                self.lprovider_undelegations(&provider),
+            );
+        }
+        let current_epoch = self.blockchain().get_block_epoch();
+        self.remove_undelegations(
+            self.total_withdrawn_egld().get(),
+            current_epoch,
+            self.lprovider_undelegations(&provider),
+            UndelegationType::ProviderList,
+            provider.clone(),
+        );
+        let mut list = self.lprovider_undelegations(&provider);
+        let mut amount_not_sent = egld_to_undelegate;
+        while amount_not_sent > 0 {
+            let node = match list.back() {
+                Some(node) => node,
+                None => break,
+            };
+            let mut undelegation = node.get_value_cloned();
+            if undelegation.amount > amount_not_sent {
+                undelegation.amount -= &amount_not_sent;
+                list.set_node_value(node, undelegation);
+                break
+            }
+
+            amount_not_sent -= &undelegation.amount;
+            list.remove_node(&node);
+        }
+
+        self.import_legacy_provider_event(
+            &self.blockchain().get_caller(),
+            &provider,
+            weight,
+            &total_staked,
+            &self.get_undelegations_amount(list),
+            current_epoch,
+            &self.token_price(),
+        );
+    }
+
+    #[only_owner]
+    #[endpoint(startProviderMigration)]
+    fn start_provider_migration(&self, source: ManagedAddress, target: ManagedAddress) {
+        require!(self.is_state_active(), ERROR_NOT_ACTIVE);
+        require!(
+            self.migration_source().is_empty(),
+            ERROR_MIGRATION_IN_PROGRESS
+        );
+        require!(source != target, ERROR_SAME_PROVIDER);
+
+        // move the source weight to the target, so new delegations go there
+        let mut source_provider = self.get_provider(&source);
+        let egld_to_migrate = source_provider.total_staked.clone();
+        require!(egld_to_migrate > 0, ERROR_NOTHING_TO_MIGRATE);
+
+        let source_weight = source_provider.weight;
+        let is_new_target = !self.providers().contains_key(&target);
+        let target_provider = match self.providers().get(&target) {
+            Some(mut provider) => {
+                provider.weight += source_provider.weight;
+                provider
+            }
+            None => ProviderConfig {
+                weight: source_provider.weight,
+                total_staked: BigUint::zero(),
+            },
+        };
+        self.providers().insert(target.clone(), target_provider);
+        source_provider.weight = 0;
+        source_provider.total_staked = BigUint::zero();
+        self.providers().insert(source.clone(), source_provider);
+
+        self.migration_source().set(&source);
+        self.migration_target().set(&target);
+        let current_epoch = self.blockchain().get_block_epoch();
+        let unbond_epoch = current_epoch + self.unbond_period().get();
+        self.add_undelegation(
+            egld_to_migrate.clone(),
+            unbond_epoch,
+            self.lmigration_undelegations(),
+        );
+        self.start_provider_migration_event(
+            &self.blockchain().get_caller(),
+            &source,
+            &target,
+            &egld_to_migrate,
+            current_epoch,
+            &self.token_price(),
+        );
+
+        self.start_provider_call(&source);
+        let gas_for_async_call = self.get_gas_for_async_call();
+        self.delegation_proxy_obj()
+            .contract(source.clone())
+            .undelegate(egld_to_migrate.clone())
+            .with_gas_limit(gas_for_async_call)
+            .async_call()
+            .with_callback(
+                SalsaContract::callbacks(self).migration_undelegate_callback(
+                    self.blockchain().get_caller(),
+                    source,
+                    egld_to_migrate,
+                    source_weight,
+                    is_new_target,
+                ),
+            )
+            .call_and_exit()
+    }
+
+    #[callback]
+    fn migration_undelegate_callback(
+        &self,
+        caller: ManagedAddress,
+        provider: ManagedAddress,
+        egld_to_migrate: BigUint,
+        source_weight: u64,
+        is_new_target: bool,
+        #[call_result] result: ManagedAsyncCallResult<()>,
+    ) {
+        self.end_provider_call(&provider);
+        match result {
+            ManagedAsyncCallResult::Ok(()) => {
+                let current_epoch = self.blockchain().get_block_epoch();
+                let unbond_epoch = current_epoch + self.unbond_period().get();
+                self.add_undelegation(
+                    egld_to_migrate.clone(),
+                    unbond_epoch,
+                    self.lprovider_undelegations(&provider),
+                );
+                self.migration_undelegate_event(
+                    &caller,
+                    &provider,
+                    &egld_to_migrate,
+                    current_epoch,
+                    unbond_epoch,
+                    &self.token_price(),
+                );
+            }
+            ManagedAsyncCallResult::Err(_) => {
+                // move the weight back to the source, keep the new target if it already got stake
+                let target = self.migration_target().get();
+                let mut target_provider = self.get_provider(&target);
+                target_provider.weight -= source_weight;
+                if is_new_target
+                    && target_provider.total_staked == 0
+                    && self.lprovider_undelegations(&target).is_empty()
+                {
+                    self.delete_provider(&target);
+                } else {
+                    self.providers().insert(target, target_provider);
+                }
+                let mut source_provider = self.get_provider(&provider);
+                source_provider.weight = source_weight;
+                source_provider.total_staked += &egld_to_migrate;
+                self.providers().insert(provider.clone(), source_provider);
+                self.lmigration_undelegations().clear();
+                self.migration_source().clear();
+                self.migration_target().clear();
+                self.migration_undelegate_failed_event(
+                    &caller,
+                    &provider,
+                    &egld_to_migrate,
+                    self.blockchain().get_block_epoch(),
+                    &self.token_price(),
+                );
+            }
+        }
+    }
+
+    #[endpoint(delegateMigratedEgld)]
+    fn delegate_migrated_egld(&self) {
+        require!(self.is_exit_allowed(), ERROR_NOT_ACTIVE);
+
+        let egld_to_migrate = self.egld_to_migrate().get();
+        require!(egld_to_migrate > 0, ERROR_NOTHING_TO_MIGRATE);
+
+        self.egld_to_migrate().clear();
+        let delegation_contract = self.migration_target().get();
+        self.add_provider_stake(&delegation_contract, &egld_to_migrate);
+
+        self.start_provider_call(&delegation_contract);
+        let gas_for_async_call = self.get_gas_for_async_call();
+        self.delegation_proxy_obj()
+            .contract(delegation_contract.clone())
+            .delegate()
+            .with_gas_limit(gas_for_async_call)
+            .with_egld_transfer(egld_to_migrate.clone())
+            .async_call()
+            .with_callback(
+                SalsaContract::callbacks(self).delegate_migrated_egld_callback(
+                    self.blockchain().get_caller(),
+                    delegation_contract,
+                    egld_to_migrate,
+                ),
+            )
+            .call_and_exit()
+    }
+
+    #[callback]
+    fn delegate_migrated_egld_callback(
+        &self,
+        caller: ManagedAddress,
+        provider: ManagedAddress,
+        egld_to_migrate: BigUint,
+        #[call_result] result: ManagedAsyncCallResult<()>,
+    ) {
+        self.end_provider_call(&provider);
+        match result {
+            ManagedAsyncCallResult::Ok(()) => {
+                self.delegate_migrated_egld_event(
+                    &caller,
+                    &provider,
+                    &egld_to_migrate,
+                    self.blockchain().get_block_epoch(),
+                    &self.token_price(),
+                );
+                self.check_migration_finished();
+            }
+            ManagedAsyncCallResult::Err(_) => {
+                self.remove_provider_stake(&provider, &egld_to_migrate);
+                self.egld_to_migrate()
+                    .update(|value| *value += &egld_to_migrate);
+                self.delegate_migrated_egld_failed_event(
+                    &caller,
+                    &provider,
+                    &egld_to_migrate,
+                    self.blockchain().get_block_epoch(),
+                    &self.token_price(),
+                );
+            }
+        }
+    }
+
+    // the migrated EGLD first covers pending undelegations, the rest goes to the new provider
+    fn withdraw_migrated_egld(&self, withdrawn_amount: BigUint) -> BigUint {
+        let current_epoch = self.blockchain().get_block_epoch();
+        let (left_amount, _) = self.remove_undelegations(
+            withdrawn_amount.clone(),
+            current_epoch,
+            self.lmigration_undelegations(),
+            UndelegationType::MigrationList,
+            self.migration_source().get(),
+        );
+        let migrated_amount = &withdrawn_amount - &left_amount;
+        let egld_to_undelegate = self.egld_to_undelegate().get();
+        let covered_amount = if migrated_amount > egld_to_undelegate {
+            egld_to_undelegate.clone()
+        } else {
+            migrated_amount.clone()
+        };
+        self.egld_to_undelegate()
+            .set(&egld_to_undelegate - &covered_amount);
+        self.egld_to_migrate()
+            .update(|value| *value += &migrated_amount - &covered_amount);
+        self.check_migration_finished();
+
+        left_amount + covered_amount
+    }
+
+    fn check_migration_finished(&self) {
+        if self.migration_source().is_empty()
+            || !self.lmigration_undelegations().is_empty()
+            || self.egld_to_migrate().get() > 0
+        {
+            return
+        }
+
+        let source = self.migration_source().take();
+        self.migration_target().clear();
+        let provider = self.get_provider(&source);
+        if provider.total_staked == 0 && self.lprovider_undelegations(&source).is_empty() {
+            self.delete_provider(&source);
+        }
+    }
+
+    // views
+
+    // the service calls that would succeed now, with pending delegations and undelegations netted
+    #[view(getPendingOperations)]
+    fn get_pending_operations(&self) -> PendingOperations<Self::Api> {
+        let mut egld_to_delegate = self.egld_to_delegate().get();
+        let mut egld_to_undelegate = self.egld_to_undelegate().get();
+        let mut withdrawn_egld = self.total_withdrawn_egld().get();
+        let netted_amount = if egld_to_delegate > egld_to_undelegate {
+            egld_to_undelegate.clone()
+        } else {
+            egld_to_delegate.clone()
+        };
+        egld_to_delegate -= &netted_amount;
+        egld_to_undelegate -= &netted_amount;
+        withdrawn_egld += netted_amount;
+
+        let matured_users_undelegations =
+            self.get_matured_undelegations_amount(self.ltotal_user_undelegations());
+        let matured_reserves_undelegations =
+            self.get_matured_undelegations_amount(self.lreserve_undelegations());
+        let is_active = self.is_state_active();
+        let is_exit_allowed = self.is_exit_allowed();
+
+        PendingOperations {
+            can_delegate_all: is_active && egld_to_delegate >= MIN_EGLD,
+            egld_to_delegate,
+            can_undelegate_all: is_exit_allowed && egld_to_undelegate >= MIN_EGLD,
+            egld_to_undelegate,
+            can_compound: is_active && self.find_compound_provider().is_some(),
+            can_withdraw_all: is_exit_allowed && self.find_withdraw_provider().is_some(),
+            can_compute_withdrawn: withdrawn_egld > 0 &&
+                (matured_users_undelegations > 0 || matured_reserves_undelegations > 0),
+            matured_users_undelegations,
+            matured_reserves_undelegations,
+            withdrawn_egld,
+        }
+    }
+
+    #[view(getContractInfo)]
+    fn get_contract_info(&self) -> ContractInfo<Self::Api> {
+        ContractInfo {
+            state: self.state().get(),
+            token_price: self.token_price(),
+            total_egld_staked: self.total_egld_staked().get(),
+            liquid_token_supply: self.liquid_token_supply().get(),
+            egld_reserve: self.get_vested_egld_reserve(),
+            available_egld_reserve: self.available_egld_reserve().get(),
+            reserve_points: self.reserve_points().get(),
+            undelegate_now_fee: self.undelegate_now_fee().get(),
+            undelegate_now_max_fee: self.undelegate_now_max_fee().get(),
+            protocol_fee: self.protocol_fee().get(),
+            unbond_period: self.unbond_period().get(),
+            egld_to_delegate: self.egld_to_delegate().get(),
+            egld_to_undelegate: self.egld_to_undelegate().get(),
+            total_withdrawn_egld: self.total_withdrawn_egld().get(),
+            user_withdrawn_egld: self.user_withdrawn_egld().get(),
+            total_losses: self.total_losses().get(),
+            providers_count: self.providers().len(),
+        }
+    }
+
+    // the user's reserve and undelegation tokens are held in the wallet, so they are passed in by
+    // the caller and not checked against the user's balance: the reserve points are used as given
+    // and the nonces are only checked to be live undelegation tokens. the legacy positions not yet
+    // migrated are added from storage
+    #[view(getUserPosition)]
+    fn get_user_position(
+        &self,
+        user: ManagedAddress,
+        reserve_points: BigUint,
+        undelegation_nonces: MultiValueEncoded<u64>,
+    ) -> UserPosition<Self::Api> {
+        let current_epoch = self.blockchain().get_block_epoch();
+        let mut undelegations = ManagedVec::<Self::Api, Undelegation<Self::Api>>::new();
+        for nonce in undelegation_nonces.into_iter() {
+            if !self.undelegation_tokens().contains(&nonce) {
+                continue
+            }
+
+            let mut undelegation: Undelegation<Self::Api> =
+                self.undelegation_token_id().get_token_attributes(nonce);
+            undelegation.amount = self.get_undelegation_token_amount(nonce, &undelegation);
+            undelegations.push(undelegation);
+        }
+        // legacy undelegations predate every loss record
+        for node in self.luser_undelegations(&user).iter() {
+            let mut undelegation = node.into_value();
+            undelegation.amount = self.get_undelegation_amount_after_losses(&undelegation, 0);
+            undelegations.push(undelegation);
+        }
+
+        let mut withdrawable_undelegations = ManagedVec::new();
+        let mut pending_undelegations = ManagedVec::new();
+        let mut withdrawable_amount = BigUint::zero();
+        for undelegation in undelegations.iter() {
+            if undelegation.unbond_epoch <= current_epoch {
+                withdrawable_amount += &undelegation.amount;
+                withdrawable_undelegations.push(undelegation);
+            } else {
+                pending_undelegations.push(undelegation);
+            }
+        }
+
+        // at most what is already withdrawn from the providers
+        let withdrawn_egld = self.user_withdrawn_egld().get() + self.total_withdrawn_egld().get();
+        if withdrawable_amount > withdrawn_egld {
+            withdrawable_amount = withdrawn_egld;
+        }
+
+        let reserve_points = reserve_points + self.users_reserve_points(&user).get();
+        let locked_reserve = match self.get_locked_reserve(&user) {
+            OptionalValue::Some(locked_reserve) => locked_reserve,
+            OptionalValue::None => LockedReserve {
+                reserve_points: BigUint::zero(),
+                lock_epochs: 0,
+                multiplier: 0,
+                unlock_epoch: 0,
+                boost_index: BigUint::zero(),
+                boost_rewards: BigUint::zero(),
+            },
+        };
+        let queued_reserve_points = self.user_queued_reserve_points(&user).get();
+        UserPosition {
+            reserve_egld: self.get_reserve_egld_amount(&reserve_points),
+            reserve_points,
+            locked_reserve_egld: self.get_reserve_egld_amount(&locked_reserve.reserve_points),
+            locked_reserve_points: locked_reserve.reserve_points,
+            unlock_epoch: locked_reserve.unlock_epoch,
+            boost_rewards: locked_reserve.boost_rewards,
+            queued_reserve_egld: self.get_reserve_egld_amount(&queued_reserve_points),
+            queued_reserve_points,
+            withdrawable_undelegations,
+            pending_undelegations,
+            withdrawable_amount,
+        }
     }
 
     // helpers
 
+    // the keeper rewards are credited from the pool, up to the epoch cap
+    fn credit_keeper_reward(&self, keeper: &ManagedAddress) {
+        let current_epoch = self.blockchain().get_block_epoch();
+        if self.keeper_rewards_epoch().get() != current_epoch {
+            self.keeper_rewards_epoch().set(current_epoch);
+            self.keeper_epoch_rewards().clear();
+        }
+
+        let epoch_rewards = self.keeper_epoch_rewards().get();
+        let max_epoch_rewards = self.keeper_max_epoch_rewards().get();
+        if epoch_rewards >= max_epoch_rewards {
+            return
+        }
+
+        let mut reward = self.keeper_reward().get();
+        let epoch_rewards_left = &max_epoch_rewards - &epoch_rewards;
+        if reward > epoch_rewards_left {
+            reward = epoch_rewards_left;
+        }
+        let pool = self.keeper_rewards_pool().get();
+        if reward > pool {
+            reward = pool;
+        }
+        if reward == 0 {
+            return
+        }
+
+        self.keeper_rewards_pool()
+            .update(|value| *value -= &reward);
+        self.keeper_epoch_rewards()
+            .set(epoch_rewards + &reward);
+        self.keeper_claimable_rewards(keeper)
+            .update(|value| *value += &reward);
+        self.keeper_reward_event(
+            keeper,
+            &reward,
+            current_epoch,
+            &self.token_price(),
+        );
+    }
+
     fn get_gas_for_async_call(&self) -> u64 {
         let gas_left = self.blockchain().get_gas_left();
         require!(
@@ -566,18 +1887,196 @@ pub trait SalsaContract<ContractReader>:
         gas_left - MIN_GAS_FOR_CALLBACK
     }
 
-    fn add_liquidity(&self, new_stake_amount: &BigUint) -> BigUint {
-        let total_egld_staked = self.total_egld_staked().get();
-        let liquid_token_supply = self.liquid_token_supply().get();
-        let ls_amount = if total_egld_staked > 0 {
-            if liquid_token_supply == 0 {
-                new_stake_amount + &total_egld_staked
+    // pending delegations and undelegations cancel each other out, without calling the providers
+    fn net_pending_egld(&self) {
+        let egld_to_delegate = self.egld_to_delegate().get();
+        let egld_to_undelegate = self.egld_to_undelegate().get();
+        let netted_amount = if egld_to_delegate > egld_to_undelegate {
+            egld_to_undelegate.clone()
+        } else {
+            egld_to_delegate.clone()
+        };
+        if netted_amount == 0 {
+            return
+        }
+
+        self.egld_to_delegate()
+            .set(&egld_to_delegate - &netted_amount);
+        self.egld_to_undelegate()
+            .set(&egld_to_undelegate - &netted_amount);
+        self.total_withdrawn_egld()
+            .update(|value| *value += netted_amount);
+    }
+
+    fn get_delegation_covered_amount(&self, egld_amount: &BigUint) -> BigUint {
+        let egld_to_delegate = self.egld_to_delegate().get();
+        if egld_amount > &egld_to_delegate {
+            egld_to_delegate
+        } else {
+            egld_amount.clone()
+        }
+    }
+
+    fn get_providers_totals(&self) -> (BigUint, u64) { // total staked, total weight
+        let mut total_staked = BigUint::zero();
+        let mut total_weight = 0u64;
+        for provider in self.providers().values() {
+            total_staked += provider.total_staked;
+            total_weight += provider.weight;
+        }
+
+        (total_staked, total_weight)
+    }
+
+    // the provider that is the most below its target weight after the delegation
+    fn get_delegate_provider(&self, amount: &BigUint) -> ManagedAddress {
+        let (total_staked, total_weight) = self.get_providers_totals();
+        require!(total_weight > 0, ERROR_NO_PROVIDER_AVAILABLE);
+
+        let new_total_staked = total_staked + amount;
+        let mut best_provider: Option<ManagedAddress> = None;
+        let mut best_deficit = BigUint::zero();
+        for (address, provider) in self.providers().iter() {
+            if provider.weight == 0 {
+                continue
+            }
+
+            let target = &new_total_staked * provider.weight / total_weight;
+            let deficit = if target > provider.total_staked {
+                target - provider.total_staked
             } else {
-                new_stake_amount * &liquid_token_supply / &total_egld_staked
+                BigUint::zero()
+            };
+            if best_provider.is_none() || deficit > best_deficit {
+                best_deficit = deficit;
+                best_provider = Some(address);
             }
+        }
+
+        match best_provider {
+            Some(address) => address,
+            None => sc_panic!(ERROR_NO_PROVIDER_AVAILABLE),
+        }
+    }
+
+    // the provider that is the most above its target weight after the undelegation
+    fn get_undelegate_provider(&self, amount: &BigUint) -> (ManagedAddress, BigUint) {
+        let (total_staked, total_weight) = self.get_providers_totals();
+        let new_total_staked = if &total_staked > amount {
+            &total_staked - amount
         } else {
-            new_stake_amount.clone()
+            BigUint::zero()
         };
+        let mut best_provider: Option<(ManagedAddress, BigUint)> = None;
+        let mut best_excess = BigUint::zero();
+        for (address, provider) in self.providers().iter() {
+            if provider.total_staked == 0 {
+                continue
+            }
+
+            let target = if total_weight > 0 {
+                &new_total_staked * provider.weight / total_weight
+            } else {
+                BigUint::zero()
+            };
+            let excess = if provider.total_staked > target {
+                &provider.total_staked - &target
+            } else {
+                BigUint::zero()
+            };
+            if best_provider.is_none() || excess > best_excess {
+                best_excess = excess;
+                best_provider = Some((address, provider.total_staked));
+            }
+        }
+
+        match best_provider {
+            Some((address, provider_staked)) => {
+                let provider_amount = if &provider_staked < amount {
+                    provider_staked
+                } else {
+                    amount.clone()
+                };
+
+                (address, provider_amount)
+            }
+            None => sc_panic!(ERROR_NO_PROVIDER_AVAILABLE),
+        }
+    }
+
+    // the first provider with matured undelegations
+    fn get_withdraw_provider(&self) -> ManagedAddress {
+        match self.find_withdraw_provider() {
+            Some(address) => address,
+            None => sc_panic!(ERROR_NOTHING_TO_WITHDRAW),
+        }
+    }
+
+    fn find_withdraw_provider(&self) -> Option<ManagedAddress> {
+        let current_epoch = self.blockchain().get_block_epoch();
+        for address in self.providers().keys() {
+            // already withdrawn in this epoch, with unpaid undelegations
+            if !self.unpaid_undelegations(&address).is_empty() &&
+                self.unpaid_undelegations_epoch(&address).get() == current_epoch {
+                continue
+            }
+
+            if let Some(first) = self.lprovider_undelegations(&address).front() {
+                if first.into_value().unbond_epoch <= current_epoch {
+                    return Some(address)
+                }
+            }
+        }
+
+        None
+    }
+
+    // the first provider whose rewards were not claimed in this epoch
+    fn find_compound_provider(&self) -> Option<ManagedAddress> {
+        let current_epoch = self.blockchain().get_block_epoch();
+        self.providers()
+            .keys()
+            .find(|provider| self.claimable_rewards_epoch(provider).get() != current_epoch)
+    }
+
+    fn add_rewards(&self, rewards: &BigUint) {
+        self.update_rate_history();
+        let protocol_fee = self.protocol_fee().get();
+        let fee_amount = if self.treasury_address().is_empty() {
+            BigUint::zero()
+        } else {
+            rewards * protocol_fee / MAX_PERCENT
+        };
+        self.total_egld_staked()
+            .update(|value| *value += &(rewards - &fee_amount));
+        self.check_price_move();
+        if fee_amount == 0 {
+            return
+        }
+
+        // the fee is minted after the rewards are added, so it doesn't dilute the holders
+        let treasury_address = self.treasury_address().get();
+        let ls_amount = self.add_liquidity(&fee_amount);
+        let fee_payment = self.mint_liquid_token(ls_amount);
+        self.total_protocol_fees()
+            .update(|value| *value += &fee_amount);
+        self.total_protocol_fee_tokens()
+            .update(|value| *value += &fee_payment.amount);
+        self.send().direct_esdt(
+            &treasury_address,
+            &fee_payment.token_identifier,
+            fee_payment.token_nonce,
+            &fee_payment.amount,
+        );
+    }
+
+    // the virtual shares and assets make donations and rounding games unprofitable,
+    // both conversions round down, in favor of the pool
+    fn add_liquidity(&self, new_stake_amount: &BigUint) -> BigUint {
+        let total_egld_staked = self.total_egld_staked().get();
+        let liquid_token_supply = self.liquid_token_supply().get();
+        let ls_amount = new_stake_amount * &(liquid_token_supply + VIRTUAL_SHARES)
+            / &(total_egld_staked + VIRTUAL_ASSETS);
 
         require!(ls_amount > 0, ERROR_NOT_ENOUGH_LIQUID_SUPPLY);
 
@@ -598,7 +2097,10 @@ pub trait SalsaContract<ContractReader>:
         );
         require!(ls_amount > &0, ERROR_BAD_PAYMENT_AMOUNT);
 
-        let egld_amount = ls_amount * &total_egld_staked / &liquid_token_supply;
+        let mut egld_amount = self.get_liquid_egld_amount(ls_amount);
+        if egld_amount > total_egld_staked {
+            egld_amount = total_egld_staked;
+        }
         require!(egld_amount > 0u64, ERROR_BAD_PAYMENT_AMOUNT);
 
         self.total_egld_staked()
@@ -609,6 +2111,11 @@ pub trait SalsaContract<ContractReader>:
         egld_amount
     }
 
+    fn get_liquid_egld_amount(&self, ls_amount: &BigUint) -> BigUint {
+        ls_amount * &(self.total_egld_staked().get() + VIRTUAL_ASSETS)
+            / &(self.liquid_token_supply().get() + VIRTUAL_SHARES)
+    }
+
     fn mint_liquid_token(&self, amount: BigUint) -> EsdtTokenPayment<Self::Api> {
         self.liquid_token_id().mint(amount)
     }
@@ -617,6 +2124,196 @@ pub trait SalsaContract<ContractReader>:
         self.liquid_token_id().burn(amount);
     }
 
+    // helpers: losses
+
+    // a loss of active stake is taken by the holders, the undelegations already promised keep
+    // their amount, including the ones not yet sent to the providers
+    fn apply_stake_loss(&self, provider: &ManagedAddress, loss: &BigUint) {
+        self.update_rate_history();
+        let reserve_part = self.cover_loss_from_reserve(loss);
+        // the covered part is staked again
+        self.egld_to_delegate()
+            .update(|value| *value += &reserve_part);
+
+        let rest = loss - &reserve_part;
+        let total_egld_staked = self.total_egld_staked().get();
+        let holders_part = if rest > total_egld_staked {
+            total_egld_staked
+        } else {
+            rest.clone()
+        };
+        self.total_egld_staked()
+            .update(|value| *value -= &holders_part);
+
+        let uncovered_part = &rest - &holders_part;
+        self.record_loss(provider, loss, &reserve_part, &holders_part, &BigUint::zero(), &uncovered_part);
+    }
+
+    // a loss of unbonding stake is shared by the matured user and reserve undelegations, the ones
+    // still unbonding are paid from later withdrawals
+    fn apply_undelegation_loss(&self, provider: &ManagedAddress, loss: &BigUint) {
+        self.update_rate_history();
+        let mut reserve_part = self.cover_loss_from_reserve(loss);
+        // the covered part pays the undelegations instead of the provider
+        self.total_withdrawn_egld()
+            .update(|value| *value += &reserve_part);
+
+        let rest = loss - &reserve_part;
+        let matured_user_undelegations =
+            self.get_matured_undelegations_amount(self.ltotal_user_undelegations())
+                + self.user_withdrawn_egld().get();
+        let matured_reserve_undelegations =
+            self.get_matured_undelegations_amount(self.lreserve_undelegations());
+        let exposed_egld = &matured_user_undelegations + &matured_reserve_undelegations;
+        let mut holders_part = BigUint::zero();
+        let mut undelegations_part = BigUint::zero();
+        if exposed_egld == 0 {
+            let total_egld_staked = self.total_egld_staked().get();
+            holders_part = if rest > total_egld_staked {
+                total_egld_staked
+            } else {
+                rest.clone()
+            };
+            self.total_egld_staked()
+                .update(|value| *value -= &holders_part);
+        } else {
+            undelegations_part = &rest * &matured_user_undelegations / &exposed_egld;
+            let mut reserve_undelegations_part = &rest - &undelegations_part;
+            if reserve_undelegations_part > matured_reserve_undelegations {
+                reserve_undelegations_part = matured_reserve_undelegations.clone();
+            }
+            if undelegations_part > matured_user_undelegations {
+                undelegations_part = matured_user_undelegations;
+            }
+            self.haircut_user_undelegations(&undelegations_part);
+            if reserve_undelegations_part > 0 {
+                self.scale_matured_undelegations(
+                    UndelegationType::ReservesList,
+                    &(&matured_reserve_undelegations - &reserve_undelegations_part),
+                    &matured_reserve_undelegations,
+                );
+                self.remove_reserve_egld(&reserve_undelegations_part);
+            }
+            reserve_part += reserve_undelegations_part;
+        }
+
+        let uncovered_part = loss - &reserve_part - &holders_part - &undelegations_part;
+        self.record_loss(provider, loss, &reserve_part, &holders_part, &undelegations_part, &uncovered_part);
+    }
+
+    fn cover_loss_from_reserve(&self, loss: &BigUint) -> BigUint {
+        let available_egld_reserve = self.available_egld_reserve().get();
+        let mut reserve_part = loss * self.reserve_loss_share().get() / MAX_PERCENT;
+        if reserve_part > available_egld_reserve {
+            reserve_part = available_egld_reserve;
+        }
+        self.available_egld_reserve()
+            .update(|value| *value -= &reserve_part);
+        self.remove_reserve_egld(&reserve_part);
+
+        reserve_part
+    }
+
+    // the loss record cuts the matured undelegation tokens, including the ones whose egld is already
+    // set aside, so the set aside part is cut too and goes back to pay the pending undelegations
+    fn haircut_user_undelegations(&self, amount: &BigUint) {
+        let matured_user_undelegations = self.get_matured_undelegations_amount(self.ltotal_user_undelegations());
+        let user_withdrawn_egld = self.user_withdrawn_egld().get();
+        let total_user_undelegations = &matured_user_undelegations + &user_withdrawn_egld;
+        if amount == &0 || total_user_undelegations == 0 {
+            return
+        }
+
+        let remaining_amount = &total_user_undelegations - amount;
+        self.scale_matured_undelegations(
+            UndelegationType::TotalUsersList,
+            &remaining_amount,
+            &total_user_undelegations,
+        );
+        let remaining_withdrawn_egld = &user_withdrawn_egld * &remaining_amount / &total_user_undelegations;
+        self.user_withdrawn_egld().set(&remaining_withdrawn_egld);
+        self.total_withdrawn_egld()
+            .update(|value| *value += user_withdrawn_egld - remaining_withdrawn_egld);
+
+        let mut losses = self.undelegations_losses();
+        let last_loss_index = if losses.is_empty() {
+            BigUint::from(LOSS_INDEX_PRECISION)
+        } else {
+            losses.get(losses.len()).loss_index
+        };
+        losses.push(&UndelegationLoss {
+            cutoff_epoch: self.blockchain().get_block_epoch(),
+            loss_index: last_loss_index * &remaining_amount / &total_user_undelegations,
+        });
+    }
+
+    fn scale_matured_undelegations(&self, list_type: UndelegationType, numerator: &BigUint, denominator: &BigUint) {
+        let current_epoch = self.blockchain().get_block_epoch();
+        let no_address = ManagedAddress::zero();
+        let list = self.get_undelegations_list(list_type, &no_address);
+        let mut clone_list = self.get_undelegations_list(list_type, &no_address);
+        for node in list.iter() {
+            let node_id = node.get_node_id();
+            let mut undelegation = node.into_value();
+            if undelegation.unbond_epoch > current_epoch {
+                continue
+            }
+
+            undelegation.amount = undelegation.amount * numerator / denominator;
+            clone_list.set_node_value_by_id(node_id, undelegation);
+        }
+    }
+
+    fn get_undelegations_amount(&self, list: LinkedListMapper<Undelegation<Self::Api>>) -> BigUint {
+        let mut total_amount = BigUint::zero();
+        for node in list.iter() {
+            total_amount += node.into_value().amount;
+        }
+
+        total_amount
+    }
+
+    fn get_matured_undelegations_amount(&self, list: LinkedListMapper<Undelegation<Self::Api>>) -> BigUint {
+        let current_epoch = self.blockchain().get_block_epoch();
+        let mut total_amount = BigUint::zero();
+        for node in list.iter() {
+            let undelegation = node.into_value();
+            if undelegation.unbond_epoch <= current_epoch {
+                total_amount += undelegation.amount;
+            }
+        }
+
+        total_amount
+    }
+
+    fn record_loss(
+        &self,
+        provider: &ManagedAddress,
+        loss: &BigUint,
+        reserve_part: &BigUint,
+        holders_part: &BigUint,
+        undelegations_part: &BigUint,
+        uncovered_part: &BigUint,
+    ) {
+        self.total_losses()
+            .update(|value| *value += loss);
+        if uncovered_part > &0 {
+            self.uncovered_losses()
+                .update(|value| *value += uncovered_part);
+        }
+        self.loss_event(
+            provider,
+            loss,
+            reserve_part,
+            holders_part,
+            undelegations_part,
+            uncovered_part,
+            self.blockchain().get_block_epoch(),
+            &self.token_price(),
+        );
+        self.check_price_move();
+    }
+
     // proxies
 
     #[proxy]
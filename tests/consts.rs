@@ -1,3 +1,5 @@
 pub const SALSA_WASM_PATH: &str = "salsa-sc/output/salsa-sc.wasm";
 
 pub static TOKEN_ID:&[u8] = b"TEST-123456";
+pub static UNDELEGATION_TOKEN_ID:&[u8] = b"UNDELEG-123456";
+pub static RESERVE_TOKEN_ID:&[u8] = b"RESERVE-123456";
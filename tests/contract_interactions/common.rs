@@ -1,16 +1,20 @@
+use salsa::SalsaContract;
+use crate::consts::*;
 use crate::{contract_setup::SalsaContractSetup, to_managed_biguint};
-use salsa::config::ConfigModule;
+use salsa::config::{ConfigModule, State, Undelegation};
 
 use multiversx_sc::{
+    contract_base::ContractBase,
     types::{
         Address,
         BigUint,
-        ManagedAddress
+        ManagedAddress,
+        MultiValueEncoded,
     }
 };
 
 use multiversx_sc_scenario::{
-    rust_biguint, DebugApi
+    managed_address, managed_token_id, num_bigint, rust_biguint, DebugApi
 };
 
 impl<SalsaContractObjBuilder> SalsaContractSetup<SalsaContractObjBuilder>
@@ -30,6 +34,20 @@ where
         new_user
     }
 
+    // a contract without payable endpoints, it can't receive EGLD transfers
+    pub fn setup_new_contract(&mut self) -> Address {
+        let big_zero = rust_biguint!(0);
+        let owner_address = self.owner_address.clone();
+        let contract_wrapper = self.blockchain_wrapper.create_sc_account(
+            &big_zero,
+            Some(&owner_address),
+            delegation_mock::contract_obj,
+            "delegation-mock.wasm",
+        );
+
+        contract_wrapper.address_ref().clone()
+    }
+
     pub fn check_total_egld_staked(&mut self, amount: num_bigint::BigUint) {
         self.blockchain_wrapper
             .execute_query(
@@ -54,6 +72,18 @@ where
             ).assert_ok();
     }
 
+    pub fn check_egld_to_delegate(&mut self, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.egld_to_delegate().get(),
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
+
     pub fn check_egld_to_undelegate(&mut self, amount: num_bigint::BigUint) {
         self.blockchain_wrapper
             .execute_query(
@@ -90,19 +120,120 @@ where
             ).assert_ok();
     }
 
-    pub fn check_user_reserve(&mut self, user: ManagedAddress<DebugApi>, amount: num_bigint::BigUint) {
+    pub fn check_user_reserve(&mut self, user: &Address, amount: num_bigint::BigUint) {
+        let user_reserve_points = self.blockchain_wrapper.get_esdt_balance(user, RESERVE_TOKEN_ID, 0);
         self.blockchain_wrapper
             .execute_query(
                 &self.salsa_wrapper, |sc| {
                     assert_eq!(
-                        sc.get_reserve_egld_amount(&sc.users_reserve_points(&user).get()),
+                        sc.get_reserve_egld_amount(&to_managed_biguint(user_reserve_points)),
                         to_managed_biguint(amount)
                     );
                 }
             ).assert_ok();
     }
 
-    pub fn check_user_reserve_points(&mut self, user: ManagedAddress<DebugApi>, amount: num_bigint::BigUint) {
+    pub fn get_user_reserve(&mut self, user: &Address) -> num_bigint::BigUint {
+        let user_reserve_points = self.blockchain_wrapper.get_esdt_balance(user, RESERVE_TOKEN_ID, 0);
+        let mut user_reserve = num_bigint::BigUint::default();
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    let amount = sc.get_reserve_egld_amount(&to_managed_biguint(user_reserve_points));
+                    user_reserve = num_bigint::BigUint::from_bytes_be(amount.to_bytes_be().as_slice());
+                }
+            ).assert_ok();
+
+        user_reserve
+    }
+
+    pub fn check_user_reserve_points(&mut self, user: &Address, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper.check_esdt_balance(user, RESERVE_TOKEN_ID, &amount);
+    }
+
+    pub fn check_contract_info(
+        &mut self,
+        state: State,
+        total_egld_staked: num_bigint::BigUint,
+        egld_reserve: num_bigint::BigUint,
+        providers_count: usize,
+    ) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    let info = sc.get_contract_info();
+                    assert_eq!(info.state, state);
+                    assert_eq!(info.total_egld_staked, to_managed_biguint(total_egld_staked));
+                    assert_eq!(info.egld_reserve, to_managed_biguint(egld_reserve));
+                    assert_eq!(info.token_price, sc.token_price());
+                    assert_eq!(info.providers_count, providers_count);
+                }
+            ).assert_ok();
+    }
+
+    // the position of the tokens held by the user
+    pub fn check_user_position(
+        &mut self,
+        user: &Address,
+        reserve_egld: num_bigint::BigUint,
+        withdrawable_count: usize,
+        pending_count: usize,
+        withdrawable_amount: num_bigint::BigUint,
+    ) {
+        let nonces: Vec<u64> = self.get_user_undelegations(user)
+            .iter()
+            .map(|(nonce, _, _)| *nonce)
+            .collect();
+        let reserve_points = self.blockchain_wrapper.get_esdt_balance(user, RESERVE_TOKEN_ID, 0);
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    let mut managed_nonces = MultiValueEncoded::new();
+                    for nonce in nonces.iter() {
+                        managed_nonces.push(*nonce);
+                    }
+                    let position = sc.get_user_position(
+                        managed_address!(user),
+                        to_managed_biguint(reserve_points.clone()),
+                        managed_nonces,
+                    );
+                    assert_eq!(position.reserve_egld, to_managed_biguint(reserve_egld));
+                    assert_eq!(position.withdrawable_undelegations.len(), withdrawable_count);
+                    assert_eq!(position.pending_undelegations.len(), pending_count);
+                    assert_eq!(position.withdrawable_amount, to_managed_biguint(withdrawable_amount));
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_user_locked_and_queued_reserve(
+        &mut self,
+        user: &Address,
+        locked_reserve_egld: num_bigint::BigUint,
+        queued_reserve_egld: num_bigint::BigUint,
+    ) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    let position = sc.get_user_position(
+                        managed_address!(user),
+                        BigUint::zero(),
+                        MultiValueEncoded::new(),
+                    );
+                    assert_eq!(position.locked_reserve_egld, to_managed_biguint(locked_reserve_egld));
+                    assert_eq!(position.queued_reserve_egld, to_managed_biguint(queued_reserve_egld));
+                    assert_eq!(
+                        sc.get_locked_reserve(&managed_address!(user)).into_option().is_some(),
+                        position.locked_reserve_points > 0
+                    );
+                    assert_eq!(
+                        sc.locked_reserve_users().contains(&managed_address!(user)),
+                        position.locked_reserve_points > 0
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_legacy_user_reserve_points(&mut self, user: ManagedAddress<DebugApi>, amount: num_bigint::BigUint) {
         self.blockchain_wrapper
             .execute_query(
                 &self.salsa_wrapper, |sc| {
@@ -142,77 +273,334 @@ where
             ).assert_ok();
     }
 
-    pub fn check_user_undelegations_length(&mut self, user: ManagedAddress<DebugApi>, len: usize) {
+    pub fn check_state(&mut self, state: State) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(sc.state().get(), state);
+                }
+            ).assert_ok();
+    }
+
+    // delegateAll, unDelegateAll, compound, withdrawAll, computeWithdrawn
+    pub fn check_pending_operations(&mut self, expected: [bool; 5]) {
         self.blockchain_wrapper
             .execute_query(
                 &self.salsa_wrapper, |sc| {
+                    let operations = sc.get_pending_operations();
                     assert_eq!(
-                        sc.luser_undelegations(&user).len() == len,
-                        true
+                        [
+                            operations.can_delegate_all,
+                            operations.can_undelegate_all,
+                            operations.can_compound,
+                            operations.can_withdraw_all,
+                            operations.can_compute_withdrawn,
+                        ],
+                        expected
                     );
                 }
             ).assert_ok();
     }
 
-    pub fn check_total_users_undelegations_lengths(&mut self, len: usize) {
+    pub fn check_twap_rate(&mut self, window_epochs: u64, rate: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(sc.get_twap_rate(window_epochs), to_managed_biguint(rate));
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_apr(&mut self, window_epochs: u64, apr: u64, reserve_apr: u64) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(sc.get_liquid_apr(window_epochs), apr);
+                    assert_eq!(sc.get_reserve_apr(window_epochs), reserve_apr);
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_keeper_rewards_pool(&mut self, amount: num_bigint::BigUint) {
         self.blockchain_wrapper
             .execute_query(
                 &self.salsa_wrapper, |sc| {
                     assert_eq!(
-                        sc.ltotal_user_undelegations().len() == len,
-                        true
+                        sc.keeper_rewards_pool().get(),
+                        to_managed_biguint(amount)
                     );
                 }
             ).assert_ok();
     }
 
-    pub fn check_reserve_undelegations_lengths(&mut self, len: usize) {
+    pub fn check_reserve_fees_batches(&mut self, batches: Vec<(num_bigint::BigUint, u64)>) {
+        self.blockchain_wrapper
+            .execute_query(&self.salsa_wrapper, |sc| {
+                let result: Vec<(num_bigint::BigUint, u64)> = sc
+                    .get_reserve_fees_batches()
+                    .into_iter()
+                    .map(|batch| {
+                        let (amount, end_epoch) = batch.into_tuple();
+                        (num_bigint::BigUint::from_bytes_be(amount.to_bytes_be().as_slice()), end_epoch)
+                    })
+                    .collect();
+                assert_eq!(result, batches);
+            })
+            .assert_ok();
+    }
+
+    pub fn check_unvested_reserve_fees(&mut self, amount: num_bigint::BigUint) {
         self.blockchain_wrapper
             .execute_query(
                 &self.salsa_wrapper, |sc| {
                     assert_eq!(
-                        sc.lreserve_undelegations().len() == len,
-                        true
+                        sc.unvested_reserve_fees().get(),
+                        to_managed_biguint(amount)
                     );
                 }
             ).assert_ok();
     }
 
-    pub fn check_user_undelegations_order(&mut self, user: ManagedAddress<DebugApi>) {
+    pub fn check_unpaid_undelegations(&mut self, provider: &Address, amount: num_bigint::BigUint) {
         self.blockchain_wrapper
             .execute_query(
                 &self.salsa_wrapper, |sc| {
-                    let mut last_epoch = 0u64;
-                    let undelegations = sc.luser_undelegations(&user);
-                    for node in undelegations.iter() {
-                        let undelegation = node.into_value();
-                        assert_eq!(
-                            last_epoch < undelegation.unbond_epoch,
-                            true
-                        );
-                        last_epoch = undelegation.unbond_epoch;
+                    assert_eq!(
+                        sc.unpaid_undelegations(&managed_address!(provider)).get(),
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_keeper_claimable_rewards(&mut self, keeper: &Address, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.keeper_claimable_rewards(&managed_address!(keeper)).get(),
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_reserve_exit_claimable(&mut self, user: &Address, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.reserve_exit_claimable(&managed_address!(user)).get(),
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_total_losses(&mut self, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.total_losses().get(),
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_total_protocol_fees(&mut self, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.total_protocol_fees().get(),
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_undelegate_now_fee_quote(&mut self, amount: num_bigint::BigUint, fee: u64) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.get_undelegate_now_fee_quote(&to_managed_biguint(amount)),
+                        fee
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn get_user_undelegations(&mut self, user: &Address) -> Vec<(u64, num_bigint::BigUint, u64)> { // nonce, amount, unbond epoch
+        let mut last_nonce = 0u64;
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    last_nonce = sc.blockchain().get_current_esdt_nft_nonce(
+                        &sc.blockchain().get_sc_address(),
+                        &managed_token_id!(UNDELEGATION_TOKEN_ID)
+                    );
+                }
+            ).assert_ok();
+
+        let _ = DebugApi::dummy();
+        let mut undelegations = Vec::new();
+        for nonce in 1..=last_nonce {
+            if self.blockchain_wrapper.get_esdt_balance(user, UNDELEGATION_TOKEN_ID, nonce) == rust_biguint!(0) {
+                continue
+            }
+
+            let undelegation = self.blockchain_wrapper
+                .get_nft_attributes::<Undelegation<DebugApi>>(user, UNDELEGATION_TOKEN_ID, nonce)
+                .unwrap();
+            undelegations.push((
+                nonce,
+                num_bigint::BigUint::from_bytes_be(undelegation.amount.to_bytes_be().as_slice()),
+                undelegation.unbond_epoch
+            ));
+        }
+
+        undelegations
+    }
+
+    pub fn check_user_undelegations_length(&mut self, user: &Address, len: usize) {
+        assert_eq!(self.get_user_undelegations(user).len(), len);
+    }
+
+    // undelegation tokens, legacy reserve users, legacy undelegation users
+    pub fn check_registries_lengths(&mut self, expected: [usize; 5]) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        [
+                            sc.get_undelegation_tokens_count(),
+                            sc.get_legacy_reserve_users_count(),
+                            sc.get_legacy_undelegation_users_count(),
+                            sc.get_reserve_providers_count(),
+                            sc.get_undelegation_users_count(),
+                        ],
+                        expected
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_legacy_reserve_users_page(
+        &mut self,
+        from: usize,
+        size: usize,
+        expected: &[(&Address, num_bigint::BigUint)],
+    ) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    let page: Vec<_> = sc.get_legacy_reserve_users(from, size).into_iter().collect();
+                    assert_eq!(page.len(), expected.len());
+                    for (entry, (user, points)) in page.into_iter().zip(expected.iter()) {
+                        let (address, user_points) = entry.into_tuple();
+                        assert_eq!(address, managed_address!(*user));
+                        assert_eq!(user_points, to_managed_biguint(points.clone()));
                     }
                 }
             ).assert_ok();
     }
 
-    pub fn check_user_undelegations_amount(
-        &mut self, user: ManagedAddress<DebugApi>, amount: num_bigint::BigUint
+    pub fn check_reserve_providers_page(
+        &mut self,
+        from: usize,
+        size: usize,
+        expected: &[(&Address, num_bigint::BigUint)],
     ) {
         self.blockchain_wrapper
             .execute_query(
                 &self.salsa_wrapper, |sc| {
-                    let mut total = BigUint::zero();
-                    let undelegations = sc.luser_undelegations(&user);
-                    for node in undelegations.iter() {
-                        let undelegation = node.into_value();
-                        total += undelegation.amount;
+                    let page: Vec<_> = sc.get_reserve_providers(from, size).into_iter().collect();
+                    assert_eq!(page.len(), expected.len());
+                    for (entry, (user, points)) in page.into_iter().zip(expected.iter()) {
+                        let (address, user_points) = entry.into_tuple();
+                        assert_eq!(address, managed_address!(*user));
+                        assert_eq!(user_points, to_managed_biguint(points.clone()));
                     }
-                    assert_eq!(total, to_managed_biguint(amount));
                 }
             ).assert_ok();
     }
 
+    pub fn check_undelegation_users_page(
+        &mut self,
+        from: usize,
+        size: usize,
+        expected: &[(&Address, num_bigint::BigUint)],
+    ) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    let page: Vec<_> = sc.get_undelegation_users(from, size).into_iter().collect();
+                    assert_eq!(page.len(), expected.len());
+                    for (entry, (user, amount)) in page.into_iter().zip(expected.iter()) {
+                        let (address, user_amount) = entry.into_tuple();
+                        assert_eq!(address, managed_address!(*user));
+                        assert_eq!(user_amount, to_managed_biguint(amount.clone()));
+                    }
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_reserve_exits_count(&mut self, count: usize) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(sc.get_reserve_exits_count(), count);
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_legacy_user_undelegations_length(&mut self, user: ManagedAddress<DebugApi>, len: usize) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert!(
+                        sc.luser_undelegations(&user).len() == len
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_total_users_undelegations_lengths(&mut self, len: usize) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert!(
+                        sc.ltotal_user_undelegations().len() == len
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_reserve_undelegations_lengths(&mut self, len: usize) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert!(
+                        sc.lreserve_undelegations().len() == len
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn get_user_undelegations_amount(&mut self, user: &Address) -> num_bigint::BigUint {
+        self.get_user_undelegations(user)
+            .into_iter()
+            .map(|(_, amount, _)| amount)
+            .sum()
+    }
+
+    pub fn check_user_undelegations_amount(&mut self, user: &Address, amount: num_bigint::BigUint) {
+        assert_eq!(self.get_user_undelegations_amount(user), amount);
+    }
+
     pub fn check_total_users_undelegations_amount(
         &mut self, amount: num_bigint::BigUint
     ) {
@@ -255,9 +643,8 @@ where
                     let undelegations = sc.ltotal_user_undelegations();
                     for node in undelegations.iter() {
                         let undelegation = node.into_value();
-                        assert_eq!(
-                            last_epoch < undelegation.unbond_epoch,
-                            true
+                        assert!(
+                            last_epoch < undelegation.unbond_epoch
                         );
                         last_epoch = undelegation.unbond_epoch;
                     }
@@ -265,9 +652,8 @@ where
                     let undelegations = sc.lreserve_undelegations();
                     for node in undelegations.iter() {
                         let undelegation = node.into_value();
-                        assert_eq!(
-                            last_epoch < undelegation.unbond_epoch,
-                            true
+                        assert!(
+                            last_epoch < undelegation.unbond_epoch
                         );
                         last_epoch = undelegation.unbond_epoch;
                     }
@@ -275,22 +661,6 @@ where
             ).assert_ok();
     }
 
-    pub fn check_user_undelegations_non_zero(&mut self, user: ManagedAddress<DebugApi>) {
-        self.blockchain_wrapper
-            .execute_query(
-                &self.salsa_wrapper, |sc| {
-                    let undelegations = sc.luser_undelegations(&user);
-                    for node in undelegations.iter() {
-                        let undelegation = node.into_value();
-                        assert_eq!(
-                            undelegation.amount > 0,
-                            true
-                        );
-                    }
-                }
-            ).assert_ok();
-    }
-
     pub fn check_total_undelegations_non_zero(&mut self) {
         self.blockchain_wrapper
             .execute_query(
@@ -298,20 +668,78 @@ where
                     let undelegations = sc.ltotal_user_undelegations();
                     for node in undelegations.iter() {
                         let undelegation = node.into_value();
-                        assert_eq!(
-                            undelegation.amount > 0,
-                            true
+                        assert!(
+                            undelegation.amount > 0
                         );
                     }
                     let undelegations = sc.lreserve_undelegations();
                     for node in undelegations.iter() {
                         let undelegation = node.into_value();
-                        assert_eq!(
-                            undelegation.amount > 0,
-                            true
+                        assert!(
+                            undelegation.amount > 0
                         );
                     }
                 }
             ).assert_ok();
     }
+
+    pub fn check_provider_staked(&mut self, provider: ManagedAddress<DebugApi>, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.providers().get(&provider).unwrap().total_staked,
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_provider_undelegations_amount(&mut self, provider: ManagedAddress<DebugApi>, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.get_undelegations_amount(sc.lprovider_undelegations(&provider)),
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_uncovered_losses(&mut self, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.uncovered_losses().get(),
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_provider_registered(&mut self, provider: ManagedAddress<DebugApi>, registered: bool) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.providers().contains_key(&provider),
+                        registered
+                    );
+                }
+            ).assert_ok();
+    }
+
+    pub fn check_egld_to_migrate(&mut self, amount: num_bigint::BigUint) {
+        self.blockchain_wrapper
+            .execute_query(
+                &self.salsa_wrapper, |sc| {
+                    assert_eq!(
+                        sc.egld_to_migrate().get(),
+                        to_managed_biguint(amount)
+                    );
+                }
+            ).assert_ok();
+    }
 }
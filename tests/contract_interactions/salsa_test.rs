@@ -1,17 +1,21 @@
 use salsa::SalsaContract;
+use salsa::config::ConfigModule;
 
 use crate::consts::*;
 use crate::{contract_setup::SalsaContractSetup, to_managed_biguint};
 
 use multiversx_sc_scenario::{
-    num_bigint, rust_biguint, DebugApi,
+    managed_address, num_bigint, rust_biguint, testing_framework::TxTokenTransfer, DebugApi,
 };
 
 use multiversx_sc::{
+    contract_base::ContractBase,
     types::{
         Address,
+        MultiValueEncoded,
     },
 };
+use multiversx_sc::codec::multi_types::OptionalValue;
 
 impl<SalsaContractObjBuilder> SalsaContractSetup<SalsaContractObjBuilder>
 where
@@ -29,6 +33,19 @@ where
             .assert_ok();
     }
 
+    pub fn delegate_fail_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+        error: &str,
+    ) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &amount, |sc| {
+                sc.delegate();
+            })
+            .assert_user_error(error);
+    }
+
     pub fn undelegate_test(
         &mut self,
         sender: &Address,
@@ -36,7 +53,7 @@ where
     ) {
         self.blockchain_wrapper
             .execute_esdt_transfer(sender, &self.salsa_wrapper, TOKEN_ID, 0, &amount, |sc| {
-                sc.undelegate()
+                sc.undelegate();
             })
             .assert_ok();
     }
@@ -45,14 +62,68 @@ where
         &mut self,
         sender: &Address,
     ) {
-        let big_zero = rust_biguint!(0);
+        let mut current_epoch = 0u64;
+        self.blockchain_wrapper
+            .execute_query(&self.salsa_wrapper, |sc| {
+                current_epoch = sc.blockchain().get_block_epoch();
+            })
+            .assert_ok();
+
+        let transfers: Vec<TxTokenTransfer> = self.get_user_undelegations(sender)
+            .into_iter()
+            .filter(|(_, _, unbond_epoch)| *unbond_epoch <= current_epoch)
+            .map(|(nonce, _, _)| TxTokenTransfer {
+                token_identifier: UNDELEGATION_TOKEN_ID.to_vec(),
+                nonce,
+                value: rust_biguint!(1),
+            })
+            .collect();
         self.blockchain_wrapper
-            .execute_esdt_transfer(sender, &self.salsa_wrapper, TOKEN_ID, 0, &big_zero, |sc| {
+            .execute_esdt_multi_transfer(sender, &self.salsa_wrapper, &transfers, |sc| {
                 sc.withdraw()
             })
             .assert_ok();
     }
 
+    // replicates the unDelegate endpoint from before the undelegation tokens
+    pub fn legacy_undelegate_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+    ) {
+        self.blockchain_wrapper
+            .execute_esdt_transfer(sender, &self.salsa_wrapper, TOKEN_ID, 0, &amount, |sc| {
+                let ls_amount = to_managed_biguint(amount.clone());
+                let egld_to_undelegate = sc.remove_liquidity(&ls_amount);
+                sc.burn_liquid_token(&ls_amount);
+                let unbond_epoch = sc.blockchain().get_block_epoch() + sc.unbond_period().get();
+                sc.add_undelegation(
+                    egld_to_undelegate.clone(),
+                    unbond_epoch,
+                    sc.luser_undelegations(&managed_address!(sender))
+                );
+                sc.add_undelegation(
+                    egld_to_undelegate.clone(),
+                    unbond_epoch,
+                    sc.ltotal_user_undelegations()
+                );
+                sc.egld_to_undelegate()
+                    .update(|value| *value += &egld_to_undelegate);
+            })
+            .assert_ok();
+    }
+
+    pub fn migrate_undelegations_test(
+        &mut self,
+        sender: &Address,
+    ) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.migrate_undelegations();
+            })
+            .assert_ok();
+    }
+
     pub fn add_reserve_test(
         &mut self,
         sender: &Address,
@@ -60,24 +131,161 @@ where
     ) {
         self.blockchain_wrapper
             .execute_tx(sender, &self.salsa_wrapper, &amount, |sc| {
-                sc.add_reserve();
+                let _ = sc.add_reserve(OptionalValue::None);
+            })
+            .assert_ok();
+    }
+
+    pub fn add_locked_reserve_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+        lock_epochs: u64,
+    ) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &amount, |sc| {
+                // the reserve tokens stay locked in the contract
+                assert!(matches!(sc.add_reserve(OptionalValue::Some(lock_epochs)), OptionalValue::None));
+            })
+            .assert_ok();
+    }
+
+    pub fn add_locked_reserve_fail_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+        lock_epochs: u64,
+        error: &str,
+    ) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &amount, |sc| {
+                let _ = sc.add_reserve(OptionalValue::Some(lock_epochs));
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn unlock_reserve_test(&mut self, sender: &Address) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.unlock_reserve();
             })
             .assert_ok();
     }
 
+    pub fn unlock_reserve_fail_test(&mut self, sender: &Address, error: &str) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.unlock_reserve();
+            })
+            .assert_user_error(error);
+    }
+
     pub fn remove_reserve_test(
         &mut self,
         sender: &Address,
         amount: num_bigint::BigUint,
     ) {
-        let big_zero = rust_biguint!(0);
+        let reserve_points = self.blockchain_wrapper.get_esdt_balance(sender, RESERVE_TOKEN_ID, 0);
         self.blockchain_wrapper
-            .execute_tx(sender, &self.salsa_wrapper, &big_zero, |sc| {
+            .execute_esdt_transfer(sender, &self.salsa_wrapper, RESERVE_TOKEN_ID, 0, &reserve_points, |sc| {
                 sc.remove_reserve(to_managed_biguint(amount))
             })
             .assert_ok();
     }
 
+    pub fn remove_reserve_fail_test(&mut self, sender: &Address, amount: num_bigint::BigUint, error: &str) {
+        let reserve_points = self.blockchain_wrapper.get_esdt_balance(sender, RESERVE_TOKEN_ID, 0);
+        self.blockchain_wrapper
+            .execute_esdt_transfer(sender, &self.salsa_wrapper, RESERVE_TOKEN_ID, 0, &reserve_points, |sc| {
+                sc.remove_reserve(to_managed_biguint(amount));
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn process_reserve_exits_test(&mut self, sender: &Address, max_exits: usize) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.process_reserve_exits(max_exits);
+            })
+            .assert_ok();
+    }
+
+    pub fn cancel_reserve_exit_test(&mut self, sender: &Address, exit_id: u64) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.cancel_reserve_exit(exit_id);
+            })
+            .assert_ok();
+    }
+
+    pub fn cancel_reserve_exit_fail_test(&mut self, sender: &Address, exit_id: u64, error: &str) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.cancel_reserve_exit(exit_id);
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn claim_reserve_exit_test(&mut self, sender: &Address) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.claim_reserve_exit();
+            })
+            .assert_ok();
+    }
+
+    pub fn claim_reserve_exit_fail_test(&mut self, sender: &Address, error: &str) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.claim_reserve_exit();
+            })
+            .assert_user_error(error);
+    }
+
+    // replicates the addReserve endpoint from before the reserve token
+    pub fn legacy_add_reserve_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+    ) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &amount, |sc| {
+                let reserve_amount = to_managed_biguint(amount.clone());
+                let user_reserve_points = sc.get_reserve_points_amount(&reserve_amount);
+                sc.users_reserve_points(&managed_address!(sender))
+                    .update(|value| *value += &user_reserve_points);
+                sc.reserve_points()
+                    .update(|value| *value += user_reserve_points);
+                sc.egld_reserve().update(|value| *value += &reserve_amount);
+                sc.available_egld_reserve().update(|value| *value += &reserve_amount);
+            })
+            .assert_ok();
+    }
+
+    pub fn register_legacy_users_test(&mut self, users: &[&Address]) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                let mut managed_users = MultiValueEncoded::new();
+                for user in users {
+                    managed_users.push(managed_address!(*user));
+                }
+                sc.register_legacy_users(managed_users);
+            })
+            .assert_ok();
+    }
+
+    pub fn migrate_reserve_test(
+        &mut self,
+        sender: &Address,
+    ) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.migrate_reserve();
+            })
+            .assert_ok();
+    }
+
     pub fn undelegate_now_test(
         &mut self,
         sender: &Address,
@@ -86,7 +294,120 @@ where
     ) {
         self.blockchain_wrapper
             .execute_esdt_transfer(sender, &self.salsa_wrapper, TOKEN_ID, 0, &amount, |sc| {
-                sc.undelegate_now(to_managed_biguint(min_amount))
+                sc.undelegate_now(to_managed_biguint(min_amount), OptionalValue::None)
+            })
+            .assert_ok();
+    }
+
+    pub fn undelegate_now_partial_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+        min_amount: num_bigint::BigUint,
+    ) {
+        self.blockchain_wrapper
+            .execute_esdt_transfer(sender, &self.salsa_wrapper, TOKEN_ID, 0, &amount, |sc| {
+                sc.undelegate_now(to_managed_biguint(min_amount), OptionalValue::Some(true))
+            })
+            .assert_ok();
+    }
+
+    pub fn undelegate_now_fail_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+        min_amount: num_bigint::BigUint,
+        error: &str,
+    ) {
+        self.blockchain_wrapper
+            .execute_esdt_transfer(sender, &self.salsa_wrapper, TOKEN_ID, 0, &amount, |sc| {
+                sc.undelegate_now(to_managed_biguint(min_amount), OptionalValue::None)
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn place_undelegation_order_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+        max_fee: u64,
+    ) -> u64 {
+        let mut order_id = 0u64;
+        self.blockchain_wrapper
+            .execute_esdt_transfer(sender, &self.salsa_wrapper, TOKEN_ID, 0, &amount, |sc| {
+                order_id = sc.place_undelegation_order(max_fee);
+            })
+            .assert_ok();
+
+        order_id
+    }
+
+    pub fn place_undelegation_order_fail_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+        max_fee: u64,
+        error: &str,
+    ) {
+        self.blockchain_wrapper
+            .execute_esdt_transfer(sender, &self.salsa_wrapper, TOKEN_ID, 0, &amount, |sc| {
+                sc.place_undelegation_order(max_fee);
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn fill_undelegation_order_test(&mut self, sender: &Address, order_id: u64) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.fill_undelegation_order(order_id);
+            })
+            .assert_ok();
+    }
+
+    pub fn fill_undelegation_order_fail_test(&mut self, sender: &Address, order_id: u64, error: &str) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.fill_undelegation_order(order_id);
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn cancel_undelegation_order_test(&mut self, sender: &Address, order_id: u64) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.cancel_undelegation_order(order_id);
+            })
+            .assert_ok();
+    }
+
+    pub fn cancel_undelegation_order_fail_test(&mut self, sender: &Address, order_id: u64, error: &str) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.cancel_undelegation_order(order_id);
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn set_undelegate_now_max_fee_test(
+        &mut self,
+        max_fee: u64,
+    ) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_undelegate_now_max_fee(max_fee);
+            })
+            .assert_ok();
+    }
+
+    pub fn delegate_all_test(
+        &mut self,
+        sender: &Address,
+    ) {
+        let big_zero = rust_biguint!(0);
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &big_zero, |sc| {
+                sc.delegate_all()
             })
             .assert_ok();
     }
@@ -127,15 +448,411 @@ where
             .assert_ok();
     }
 
-    // pub fn compound_test(
-    //     &mut self,
-    //     sender: &Address,
-    // ) {
-    //     let big_zero = rust_biguint!(0);
-    //     self.blockchain_wrapper
-    //         .execute_tx(sender, &self.salsa_wrapper, &big_zero, |sc| {
-    //             sc.compound()
-    //         })
-    //         .assert_ok();
-    // }
+    pub fn add_provider_test(
+        &mut self,
+        weight: u64,
+    ) -> Address {
+        let big_zero = rust_biguint!(0);
+        let owner_address = self.owner_address.clone();
+        let provider = Self::deploy_delegation_provider(&mut self.blockchain_wrapper, &owner_address);
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &big_zero, |sc| {
+                sc.add_provider(managed_address!(&provider), weight)
+            })
+            .assert_ok();
+
+        provider
+    }
+
+    pub fn set_provider_weight_test(
+        &mut self,
+        provider: &Address,
+        weight: u64,
+    ) {
+        let big_zero = rust_biguint!(0);
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &big_zero, |sc| {
+                sc.set_provider_weight(managed_address!(provider), weight)
+            })
+            .assert_ok();
+    }
+
+    pub fn start_provider_migration_test(
+        &mut self,
+        source: &Address,
+        target: &Address,
+    ) {
+        let big_zero = rust_biguint!(0);
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &big_zero, |sc| {
+                sc.start_provider_migration(managed_address!(source), managed_address!(target))
+            })
+            .assert_ok();
+    }
+
+    pub fn delegate_migrated_egld_test(
+        &mut self,
+        sender: &Address,
+    ) {
+        let big_zero = rust_biguint!(0);
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &big_zero, |sc| {
+                sc.delegate_migrated_egld()
+            })
+            .assert_ok();
+    }
+
+    pub fn set_state_exit_only_test(&mut self) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_state_exit_only();
+            })
+            .assert_ok();
+    }
+
+    pub fn set_state_deprecated_test(&mut self) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_state_deprecated();
+            })
+            .assert_ok();
+    }
+
+    pub fn set_state_active_test(&mut self) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_state_active();
+            })
+            .assert_ok();
+    }
+
+    // rewrites the storage as left by the single provider version of the contract
+    pub fn simulate_legacy_provider_test(&mut self, provider: &Address) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.providers().remove(&managed_address!(provider));
+                multiversx_sc::storage::mappers::StorageClearable::clear(&mut sc.lprovider_undelegations(&managed_address!(provider)));
+                sc.provider_address().set(managed_address!(provider));
+                sc.state().set(salsa::config::State::Inactive);
+            })
+            .assert_ok();
+    }
+
+    pub fn import_legacy_provider_test(&mut self, weight: u64) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.import_legacy_provider(weight);
+            })
+            .assert_ok();
+    }
+
+    pub fn import_legacy_provider_fail_test(&mut self, weight: u64, error: &str) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.import_legacy_provider(weight);
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn set_state_active_fail_test(&mut self, error: &str) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_state_active();
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn set_reconcile_tolerance_test(&mut self, tolerance: u64) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_reconcile_tolerance(tolerance);
+            })
+            .assert_ok();
+    }
+
+    pub fn reconcile_test(
+        &mut self,
+        sender: &Address,
+        provider: &Address,
+    ) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.reconcile(managed_address!(provider));
+            })
+            .assert_ok();
+    }
+
+    // simulates the tracked stake drifting from the provider's active stake
+    pub fn reconcile_fail_test(
+        &mut self,
+        sender: &Address,
+        provider: &Address,
+        error: &str,
+    ) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.reconcile(managed_address!(provider));
+            })
+            .assert_user_error(error);
+    }
+
+    // simulates a call sent to the provider whose callback didn't arrive yet
+    pub fn start_provider_call_test(&mut self, provider: &Address) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.start_provider_call(&managed_address!(provider));
+            })
+            .assert_ok();
+    }
+
+    pub fn end_provider_call_test(&mut self, provider: &Address) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.end_provider_call(&managed_address!(provider));
+            })
+            .assert_ok();
+    }
+
+    // simulates the provider losing pending undelegations
+    pub fn add_tracked_undelegation_test(
+        &mut self,
+        provider: &Address,
+        amount: num_bigint::BigUint,
+    ) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                let unbond_epoch = sc.blockchain().get_block_epoch() + sc.unbond_period().get();
+                sc.add_undelegation(
+                    to_managed_biguint(amount),
+                    unbond_epoch,
+                    sc.lprovider_undelegations(&managed_address!(provider)),
+                );
+            })
+            .assert_ok();
+    }
+
+    // simulates a loss of unbonding stake cutting the undelegations matured by cutoff_epoch
+    pub fn add_undelegations_loss_test(&mut self, cutoff_epoch: u64, loss_index: num_bigint::BigUint) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.undelegations_losses().push(&salsa::config::UndelegationLoss {
+                    cutoff_epoch,
+                    loss_index: to_managed_biguint(loss_index),
+                });
+            })
+            .assert_ok();
+    }
+
+    pub fn remove_tracked_stake_test(
+        &mut self,
+        provider: &Address,
+        amount: num_bigint::BigUint,
+    ) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.remove_provider_stake(&managed_address!(provider), &to_managed_biguint(amount));
+            })
+            .assert_ok();
+    }
+
+    // simulates the provider's active stake shrinking
+    pub fn add_tracked_stake_test(
+        &mut self,
+        provider: &Address,
+        amount: num_bigint::BigUint,
+    ) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.add_provider_stake(&managed_address!(provider), &to_managed_biguint(amount));
+            })
+            .assert_ok();
+    }
+
+    // simulates the provider slashing pending undelegations, by asking it to undelegate less
+    pub fn slash_pending_undelegations_test(
+        &mut self,
+        provider: &Address,
+        amount: num_bigint::BigUint,
+    ) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                let provider = managed_address!(provider);
+                let amount = to_managed_biguint(amount);
+                let unbond_epoch = sc.blockchain().get_block_epoch() + sc.unbond_period().get();
+                sc.egld_to_undelegate()
+                    .update(|value| *value -= &amount);
+                sc.remove_provider_stake(&provider, &amount);
+                sc.add_undelegation(amount, unbond_epoch, sc.lprovider_undelegations(&provider));
+            })
+            .assert_ok();
+    }
+
+    pub fn confirm_undelegation_loss_test(&mut self, provider: &Address) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.confirm_undelegation_loss(managed_address!(provider));
+            })
+            .assert_ok();
+    }
+
+    pub fn confirm_undelegation_loss_fail_test(&mut self, provider: &Address, error: &str) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.confirm_undelegation_loss(managed_address!(provider));
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn set_reserve_loss_share_test(&mut self, share: u64) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_reserve_loss_share(share);
+            })
+            .assert_ok();
+    }
+
+    pub fn set_reserve_fees_vesting_epochs_test(&mut self, epochs: u64) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_reserve_fees_vesting_epochs(epochs);
+            })
+            .assert_ok();
+    }
+
+    pub fn set_reserve_fees_vesting_epochs_fail_test(&mut self, epochs: u64, error: &str) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_reserve_fees_vesting_epochs(epochs);
+            })
+            .assert_user_error(error);
+    }
+
+    pub fn set_reserve_lock_tier_test(&mut self, lock_epochs: u64, multiplier: u64) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_reserve_lock_tier(lock_epochs, multiplier);
+            })
+            .assert_ok();
+    }
+
+    pub fn set_max_price_change_test(&mut self, max_price_change: u64) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_max_price_change(max_price_change);
+            })
+            .assert_ok();
+    }
+
+    pub fn set_protocol_fee_test(
+        &mut self,
+        fee: u64,
+        treasury: &Address,
+    ) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_protocol_fee(fee);
+                sc.set_treasury_address(managed_address!(treasury));
+            })
+            .assert_ok();
+    }
+
+    pub fn claim_keeper_rewards_test(&mut self, sender: &Address) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.claim_keeper_rewards();
+            })
+            .assert_ok();
+    }
+
+    pub fn set_keeper_rewards_test(
+        &mut self,
+        reward: num_bigint::BigUint,
+        max_epoch_rewards: num_bigint::BigUint,
+        rewards_share: u64,
+    ) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.set_keeper_rewards(
+                    to_managed_biguint(reward),
+                    to_managed_biguint(max_epoch_rewards),
+                    rewards_share,
+                );
+            })
+            .assert_ok();
+    }
+
+    // simulates the keeper share of the compounded rewards
+    pub fn fund_keeper_rewards_pool_test(&mut self, amount: num_bigint::BigUint) {
+        let owner_address = self.owner_address.clone();
+        self.blockchain_wrapper
+            .execute_tx(&owner_address, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.keeper_rewards_pool()
+                    .update(|value| *value += to_managed_biguint(amount));
+            })
+            .assert_ok();
+    }
+
+    // simulates the rewards received by a compound
+    pub fn add_rewards_test(
+        &mut self,
+        sender: &Address,
+        amount: num_bigint::BigUint,
+    ) {
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &rust_biguint!(0), |sc| {
+                sc.add_rewards(&to_managed_biguint(amount));
+            })
+            .assert_ok();
+    }
+
+    pub fn compound_test(
+        &mut self,
+        sender: &Address,
+    ) {
+        let big_zero = rust_biguint!(0);
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &big_zero, |sc| {
+                sc.compound()
+            })
+            .assert_ok();
+    }
+
+    pub fn compound_fail_test(
+        &mut self,
+        sender: &Address,
+        error: &str,
+    ) {
+        let big_zero = rust_biguint!(0);
+        self.blockchain_wrapper
+            .execute_tx(sender, &self.salsa_wrapper, &big_zero, |sc| {
+                sc.compound()
+            })
+            .assert_user_error(error);
+    }
 }
@@ -25,12 +25,18 @@ pub static ESDT_ROLES: &[EsdtLocalRole] = &[
     EsdtLocalRole::Transfer,
 ];
 
+pub static NFT_ROLES: &[EsdtLocalRole] = &[
+    EsdtLocalRole::NftCreate,
+    EsdtLocalRole::NftBurn,
+];
+
 pub struct SalsaContractSetup<SalsaContractObjBuilder>
 where
     SalsaContractObjBuilder: 'static + Copy + Fn() -> salsa::ContractObj<DebugApi>,
 {
     pub blockchain_wrapper: BlockchainStateWrapper,
     pub owner_address: Address,
+    pub provider_address: Address,
     pub salsa_wrapper: ContractObjWrapper<salsa::ContractObj<DebugApi>, SalsaContractObjBuilder>
 }
 
@@ -74,37 +80,38 @@ where
             ESDT_ROLES
         );
 
-        // deploy delegation sc
-        let delegation_wrapper = blockchain_wrapper.create_sc_account(
-            &big_zero,
-            Some(&owner_address),
-            delegation_mock::contract_obj,
-            "delegation-mock.wasm",
-        );
-
+        // create reserve token
         blockchain_wrapper
-            .execute_tx(&owner_address, &delegation_wrapper, &big_zero, |sc| {
-                sc.init();
+            .execute_tx(&owner_address, &salsa_wrapper, &big_zero, |sc|{
+                sc.reserve_token_id().set_token_id(managed_token_id!(RESERVE_TOKEN_ID));
             })
             .assert_ok();
 
+        blockchain_wrapper.set_esdt_local_roles(
+            salsa_wrapper.address_ref(),
+            RESERVE_TOKEN_ID,
+            ESDT_ROLES
+        );
+
+        // create undelegation token
         blockchain_wrapper
-            .execute_tx(
-                &owner_address,
-                &delegation_wrapper,
-                &Self::exp18(1000),
-                |sc| {
-                    sc.deposit_egld();
-                },
-            )
+            .execute_tx(&owner_address, &salsa_wrapper, &big_zero, |sc|{
+                sc.undelegation_token_id().set_token_id(managed_token_id!(UNDELEGATION_TOKEN_ID));
+            })
             .assert_ok();
 
-        // set provider address
-        let provider = delegation_wrapper.address_ref().clone();
+        blockchain_wrapper.set_esdt_local_roles(
+            salsa_wrapper.address_ref(),
+            UNDELEGATION_TOKEN_ID,
+            NFT_ROLES
+        );
+
+        // deploy delegation sc and set it as provider
+        let provider = Self::deploy_delegation_provider(&mut blockchain_wrapper, &owner_address);
         blockchain_wrapper
             .execute_tx(&owner_address, &salsa_wrapper, &big_zero, |sc|{
                 let provider_address = managed_address!(&provider);
-                sc.set_provider_address(provider_address)
+                sc.add_provider(provider_address, 1u64)
             })
             .assert_ok();
 
@@ -124,6 +131,13 @@ where
             })
             .assert_ok();
 
+        // set reserve fees vesting epochs
+        blockchain_wrapper
+            .execute_tx(&owner_address, &salsa_wrapper, &big_zero, |sc|{
+                sc.set_reserve_fees_vesting_epochs(1_u64)
+            })
+            .assert_ok();
+
         // set state active
         blockchain_wrapper
             .execute_tx(&owner_address, &salsa_wrapper, &big_zero, |sc|{
@@ -134,10 +148,45 @@ where
         SalsaContractSetup {
             blockchain_wrapper,
             owner_address,
+            provider_address: provider,
             salsa_wrapper,
         }
     }
 
+    pub fn deploy_delegation_provider(
+        blockchain_wrapper: &mut BlockchainStateWrapper,
+        owner_address: &Address,
+    ) -> Address {
+        let big_zero = rust_biguint!(0u64);
+        blockchain_wrapper
+            .set_egld_balance(owner_address, &Self::exp18(1000));
+        let delegation_wrapper = blockchain_wrapper.create_sc_account(
+            &big_zero,
+            Some(owner_address),
+            delegation_mock::contract_obj,
+            "delegation-mock.wasm",
+        );
+
+        blockchain_wrapper
+            .execute_tx(owner_address, &delegation_wrapper, &big_zero, |sc| {
+                sc.init();
+            })
+            .assert_ok();
+
+        blockchain_wrapper
+            .execute_tx(
+                owner_address,
+                &delegation_wrapper,
+                &Self::exp18(1000),
+                |sc| {
+                    sc.deposit_egld();
+                },
+            )
+            .assert_ok();
+
+        delegation_wrapper.address_ref().clone()
+    }
+
     pub fn exp18(value: u64) -> num_bigint::BigUint {
         value.mul(rust_biguint!(10).pow(18))
     }
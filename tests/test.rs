@@ -4,6 +4,7 @@ mod consts;
 
 use consts::*;
 use contract_setup::*;
+use salsa::config::State;
 use multiversx_sc_scenario::{
     DebugApi
 };
@@ -43,6 +44,11 @@ fn delegation_test() {
     sc_setup.blockchain_wrapper.check_esdt_balance(&caller, TOKEN_ID, &amount);
     sc_setup.check_total_egld_staked(amount.clone());
     sc_setup.check_liquid_supply(amount.clone());
+    sc_setup.check_egld_to_delegate(amount.clone());
+
+    // delegate all
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.check_egld_to_delegate(big_zero.clone());
 
     // undelegate
     sc_setup.undelegate_test(&caller, amount.clone());
@@ -92,6 +98,7 @@ fn reserves_test() {
     sc_setup.blockchain_wrapper.check_esdt_balance(&caller, TOKEN_ID, &one);
     sc_setup.check_total_egld_staked(one.clone());
     sc_setup.check_liquid_supply(one.clone());
+    sc_setup.delegate_all_test(&caller);
 
     // add reserve
     sc_setup.add_reserve_test(&reserver, one.clone());
@@ -124,12 +131,12 @@ fn reserves_test() {
     sc_setup.remove_reserve_test(&reserver, one_plus_fee);
     sc_setup.check_egld_reserve(big_zero.clone());
     sc_setup.check_available_egld_reserve(big_zero.clone());
-    sc_setup.check_user_reserve(managed_address!(&reserver), big_zero.clone());
-    sc_setup.check_user_reserve_points(managed_address!(&reserver), big_zero.clone());
+    sc_setup.check_user_reserve(&reserver, big_zero.clone());
+    sc_setup.check_user_reserve_points(&reserver, big_zero.clone());
 }
 
 #[test]
-fn reserve_to_user_undelegation_test() {
+fn reserve_exit_queue_test() {
     let _ = DebugApi::dummy();
 
     let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
@@ -154,6 +161,7 @@ fn reserve_to_user_undelegation_test() {
     // delegate 5 and add reserves 5
     sc_setup.delegate_test(&delegator1, one.clone());
     sc_setup.delegate_test(&delegator2, one.clone() * 4u64);
+    sc_setup.delegate_all_test(&caller);
     sc_setup.add_reserve_test(&reserver1, one.clone() * 2u64);
     sc_setup.add_reserve_test(&reserver2, one.clone() * 3u64);
     // stake = 5, reserve = 5, available reserve = 5
@@ -165,11 +173,15 @@ fn reserve_to_user_undelegation_test() {
     sc_setup.undelegate_test(&delegator2, one.clone());
     // stake = 1, reserve = 5.06, available reserve = 2.06
 
-    // remove reserves 3.04
+    // remove reserves 3.06, the part above the available reserve is queued
     sc_setup.blockchain_wrapper.set_block_epoch(2u64);
     sc_setup.remove_reserve_test(&reserver1, exp(1024u64, 15));
-    sc_setup.remove_reserve_test(&reserver2, exp(2016u64, 15));
-    // stake = 1, reserve = 2.02, available reserve = 0
+    // queued exits must be worth at least the minimum
+    sc_setup.remove_reserve_fail_test(&reserver2, exp(2016u64, 15), "Queued reserve exit below the minimum");
+    sc_setup.remove_reserve_test(&reserver2, exp(2036u64, 15));
+    sc_setup.check_reserve_exits_count(1);
+    sc_setup.check_user_locked_and_queued_reserve(&reserver2, rust_biguint!(0), exp(1, 18));
+    // stake = 1, reserve = 3, available reserve = 0
 
     // check delegators balances
     sc_setup.blockchain_wrapper.check_egld_balance(&delegator1, &(exp(998u64, 16)));
@@ -180,11 +192,17 @@ fn reserve_to_user_undelegation_test() {
     // check egld staked and reserve
     sc_setup.check_total_egld_staked(one.clone());
     sc_setup.check_available_egld_reserve(big_zero.clone());
-    sc_setup.check_egld_reserve(exp(202u64, 16));
-    sc_setup.check_user_undelegations_order(managed_address!(&reserver2));
-    sc_setup.check_user_undelegations_order(managed_address!(&delegator2));
+    sc_setup.check_egld_reserve(exp(3u64, 18));
     sc_setup.check_total_undelegations_order();
 
+    // later exits wait behind the queue and can be cancelled
+    sc_setup.remove_reserve_test(&reserver1, one.clone());
+    sc_setup.check_reserve_exits_count(2);
+    sc_setup.cancel_reserve_exit_fail_test(&reserver2, 2, "Not the reserve exit owner");
+    sc_setup.cancel_reserve_exit_test(&reserver1, 2);
+    sc_setup.cancel_reserve_exit_fail_test(&reserver1, 2, "Reserve exit not found");
+    sc_setup.check_user_reserve(&reserver1, one.clone());
+
     // undelegate and withdraw
     sc_setup.blockchain_wrapper.set_block_epoch(3u64);
     sc_setup.undelegate_all_test(&caller);
@@ -195,15 +213,99 @@ fn reserve_to_user_undelegation_test() {
     sc_setup.withdraw_all_test(&caller);
     sc_setup.compute_withdrawn_test(&caller);
     sc_setup.withdraw_test(&delegator2);
-    sc_setup.withdraw_test(&reserver2);
+    sc_setup.process_reserve_exits_test(&caller, 10);
+    sc_setup.check_reserve_exits_count(0);
+    sc_setup.check_reserve_exit_claimable(&reserver2, exp(1, 18));
+    sc_setup.claim_reserve_exit_test(&reserver2);
+    sc_setup.claim_reserve_exit_fail_test(&reserver2, "Nothing to claim");
 
     // final checks
     sc_setup.blockchain_wrapper.check_egld_balance(&delegator2, &(exp(896u64, 16)));
     sc_setup.blockchain_wrapper.check_egld_balance(&reserver1, &(exp(9024u64, 15)));
-    sc_setup.blockchain_wrapper.check_egld_balance(&reserver2, &(exp(9016u64, 15)));
-    sc_setup.check_available_egld_reserve(exp(202u64, 16));
-    sc_setup.check_user_reserve(managed_address!(&reserver1), one.clone());
-    // sc_setup.check_user_reserve(managed_address!(&reserver2), exp(102u64, 16));
+    sc_setup.blockchain_wrapper.check_egld_balance(&reserver2, &exp(9036u64, 15));
+    sc_setup.check_available_egld_reserve(exp(2, 18));
+    sc_setup.check_user_reserve(&reserver1, one.clone());
+}
+
+#[test]
+fn reserve_exit_non_payable_owner_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let big_zero = rust_biguint!(0);
+    let ten = exp(10, 18);
+    let one = exp(1, 18);
+
+    let delegator1 = sc_setup.setup_new_user(1u64);
+    let delegator2 = sc_setup.setup_new_user(2u64);
+    let reserver1 = sc_setup.setup_new_user(3u64);
+    let reserver2 = sc_setup.setup_new_user(4u64);
+    let caller = sc_setup.setup_new_user(5u64);
+    let keeper = sc_setup.setup_new_user(1u64);
+    let contract = sc_setup.setup_new_contract();
+
+    // set epoch and balances
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+    sc_setup.blockchain_wrapper.set_egld_balance(&delegator1, &ten);
+    sc_setup.blockchain_wrapper.set_egld_balance(&delegator2, &ten);
+    sc_setup.blockchain_wrapper.set_egld_balance(&reserver1, &ten);
+    sc_setup.blockchain_wrapper.set_egld_balance(&reserver2, &ten);
+    sc_setup.blockchain_wrapper.set_egld_balance(&caller, &one);
+    sc_setup.set_keeper_rewards_test(exp(1, 16), exp(1, 17), 0);
+    sc_setup.fund_keeper_rewards_pool_test(exp(1, 17));
+
+    // delegate 5 and add reserves 5
+    sc_setup.delegate_test(&delegator1, one.clone());
+    sc_setup.delegate_test(&delegator2, one.clone() * 4u64);
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.add_reserve_test(&reserver1, one.clone() * 2u64);
+    sc_setup.add_reserve_test(&reserver2, one.clone() * 3u64);
+
+    // reserver2 moves the reserve tokens to the contract
+    let reserve_points = sc_setup.blockchain_wrapper.get_esdt_balance(&reserver2, RESERVE_TOKEN_ID, 0);
+    sc_setup.blockchain_wrapper.set_esdt_balance(&contract, RESERVE_TOKEN_ID, &reserve_points);
+    sc_setup.blockchain_wrapper.set_esdt_balance(&reserver2, RESERVE_TOKEN_ID, &big_zero);
+
+    // undelegate now 3
+    sc_setup.undelegate_now_test(&delegator1, one.clone(), exp(98u64, 16));
+    sc_setup.undelegate_all_test(&caller);
+    sc_setup.undelegate_now_test(&delegator2, one.clone() * 2u64, exp(196u64, 16));
+    // stake = 2, reserve = 5.06, available reserve = 2.06
+
+    // the contract exit is at the front of the queue, reserver1 waits behind it
+    sc_setup.blockchain_wrapper.set_block_epoch(2u64);
+    sc_setup.remove_reserve_test(&reserver1, exp(1012u64, 15));
+    sc_setup.remove_reserve_test(&contract, exp(3036u64, 15));
+    let remaining_reserve = sc_setup.get_user_reserve(&reserver1);
+    sc_setup.remove_reserve_test(&reserver1, remaining_reserve.clone());
+    sc_setup.check_reserve_exits_count(2);
+    sc_setup.check_available_egld_reserve(big_zero.clone());
+    sc_setup.blockchain_wrapper.check_egld_balance(&contract, &exp(1048u64, 15));
+
+    // undelegate and withdraw
+    sc_setup.blockchain_wrapper.set_block_epoch(3u64);
+    sc_setup.undelegate_all_test(&caller);
+    sc_setup.blockchain_wrapper.set_block_epoch(13u64);
+    sc_setup.withdraw_all_test(&caller);
+
+    // the fills are credited, nothing is sent to the contract
+    sc_setup.process_reserve_exits_test(&keeper, 1);
+    // computing the withdrawn amounts and filling the exits is rewarded once
+    sc_setup.check_keeper_claimable_rewards(&keeper, exp(1, 16));
+    sc_setup.check_reserve_exits_count(1);
+    sc_setup.blockchain_wrapper.check_egld_balance(&contract, &exp(1048u64, 15));
+    sc_setup.check_reserve_exit_claimable(&contract, exp(1988u64, 15));
+    sc_setup.process_reserve_exits_test(&keeper, 1);
+    sc_setup.check_reserve_exits_count(0);
+    sc_setup.check_reserve_exit_claimable(&reserver1, exp(1012u64, 15));
+    // calls without progress aren't rewarded
+    sc_setup.process_reserve_exits_test(&keeper, 1);
+    sc_setup.check_keeper_claimable_rewards(&keeper, exp(2, 16));
+
+    // reserver1 claims
+    sc_setup.claim_reserve_exit_test(&reserver1);
+    sc_setup.check_reserve_exit_claimable(&reserver1, big_zero.clone());
+    sc_setup.blockchain_wrapper.check_egld_balance(&reserver1, &exp(10024u64, 15));
 }
 
 #[test]
@@ -226,6 +328,7 @@ fn merge_undelegations_test() {
 
     // delegate and add reserve
     sc_setup.delegate_test(&delegator, one.clone() * 250u64);
+    sc_setup.delegate_all_test(&caller);
     sc_setup.add_reserve_test(&reserver, one.clone() * 125u64);
 
     // undelegate and undelegate now reserve in 15 epochs
@@ -236,10 +339,9 @@ fn merge_undelegations_test() {
         sc_setup.blockchain_wrapper.set_block_epoch(epoch);
     }
 
-    // check undelegations lenghts and order
-    sc_setup.check_user_undelegations_order(managed_address!(&delegator));
+    // check undelegations lenghts and order (user undelegation tokens are never merged)
     sc_setup.check_total_undelegations_order();
-    sc_setup.check_user_undelegations_length(managed_address!(&delegator), 11);
+    sc_setup.check_user_undelegations_length(&delegator, 15);
     sc_setup.check_total_users_undelegations_lengths(11);
     sc_setup.check_reserve_undelegations_lengths(11);
 
@@ -275,6 +377,7 @@ fn user_undelegations_order_test() {
 
     // delegate
     sc_setup.delegate_test(&delegator, exp(100, 18));
+    sc_setup.delegate_all_test(&caller);
 
     // undelegate in epochs 3 and 2 (3 times, 2 in the same epoch, so should be merged)
     epoch = 3u64;
@@ -289,9 +392,8 @@ fn user_undelegations_order_test() {
     sc_setup.undelegate_test(&delegator, one.clone());
 
     // check undelegations orders and lengths
-    sc_setup.check_user_undelegations_order(managed_address!(&delegator));
     sc_setup.check_total_undelegations_order();
-    sc_setup.check_user_undelegations_length(managed_address!(&delegator), 3);
+    sc_setup.check_user_undelegations_length(&delegator, 4);
     sc_setup.check_total_users_undelegations_lengths(3);
 
     // undelegate in epoch 1, 3, 5, 30 and 15
@@ -312,11 +414,10 @@ fn user_undelegations_order_test() {
     sc_setup.undelegate_test(&delegator, one.clone());
 
     // check undelegations orders, lengths and amount
-    sc_setup.check_user_undelegations_order(managed_address!(&delegator));
     sc_setup.check_total_undelegations_order();
-    sc_setup.check_user_undelegations_length(managed_address!(&delegator), 3);
+    sc_setup.check_user_undelegations_length(&delegator, 9);
     sc_setup.check_total_users_undelegations_lengths(3);
-    sc_setup.check_user_undelegations_amount(managed_address!(&delegator), exp(9, 18));
+    sc_setup.check_user_undelegations_amount(&delegator, exp(9, 18));
     sc_setup.check_total_users_undelegations_amount(exp(9, 18));
 
     // undelegate all, withdraw and compute withdrawn
@@ -329,10 +430,9 @@ fn user_undelegations_order_test() {
     sc_setup.compute_withdrawn_test(&caller);
     sc_setup.withdraw_test(&delegator);
     sc_setup.check_total_undelegations_non_zero();
-    sc_setup.check_user_undelegations_non_zero(managed_address!(&delegator));
 
     // check undelegations lengths
-    sc_setup.check_user_undelegations_length(managed_address!(&delegator), 0);
+    sc_setup.check_user_undelegations_length(&delegator, 0);
     sc_setup.check_reserve_undelegations_lengths(0);
     sc_setup.check_total_users_undelegations_lengths(0);
 }
@@ -355,6 +455,7 @@ fn reserve_undelegations_order_test() {
 
     // delegate and add reserve
     sc_setup.delegate_test(&reserver, exp(50, 18));
+    sc_setup.delegate_all_test(&caller);
     sc_setup.add_reserve_test(&reserver, exp(50, 18));
 
     // undelegate now in epochs 3 and 2 (3 times, 2 in the same epoch, so should be merged)
@@ -415,11 +516,12 @@ fn check_non_zero_undelegations() {
 
     // delegate, then undelegate
     sc_setup.delegate_test(&delegator, one.clone());
+    sc_setup.delegate_all_test(&delegator);
     sc_setup.undelegate_test(&delegator, one.clone());
     sc_setup.undelegate_all_test(&delegator);
 
     // check
-    sc_setup.check_user_undelegations_amount(managed_address!(&delegator), one.clone());
+    sc_setup.check_user_undelegations_amount(&delegator, one.clone());
     sc_setup.check_total_users_undelegations_amount(one.clone());
 
     // withdraw_all then check again
@@ -427,14 +529,1020 @@ fn check_non_zero_undelegations() {
     sc_setup.blockchain_wrapper.set_block_epoch(epoch);
     sc_setup.withdraw_all_test(&delegator);
     sc_setup.compute_withdrawn_test(&delegator);
-    sc_setup.check_user_undelegations_amount(managed_address!(&delegator), one.clone());
+    sc_setup.check_user_undelegations_amount(&delegator, one.clone());
     sc_setup.check_total_users_undelegations_amount(zero.clone());
 
     // withdraw then final check
     sc_setup.withdraw_test(&delegator);
-    sc_setup.check_user_undelegations_amount(managed_address!(&delegator), zero);
+    sc_setup.check_user_undelegations_amount(&delegator, zero);
     sc_setup.check_total_undelegations_non_zero();
-    sc_setup.check_user_undelegations_non_zero(managed_address!(&delegator));
+}
+
+#[test]
+fn multiple_providers_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let big_zero = rust_biguint!(0);
+    let delegator = sc_setup.setup_new_user(30u64);
+    let caller = sc_setup.setup_new_user(1u64);
+    let provider1 = sc_setup.provider_address.clone();
+    let provider2 = sc_setup.add_provider_test(1u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // delegations go to the provider that is the most below its target
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.delegate_test(&delegator, exp(4, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.check_provider_staked(managed_address!(&provider1), exp(14, 18));
+    sc_setup.check_provider_staked(managed_address!(&provider2), exp(10, 18));
+    sc_setup.check_total_egld_staked(exp(24, 18));
+
+    // undelegations come from the provider that is the most above its target
+    sc_setup.undelegate_test(&delegator, exp(6, 18));
+    sc_setup.undelegate_all_test(&caller);
+    sc_setup.check_provider_staked(managed_address!(&provider1), exp(8, 18));
+    sc_setup.check_provider_staked(managed_address!(&provider2), exp(10, 18));
+
+    // a provider with zero weight is drained first
+    sc_setup.set_provider_weight_test(&provider2, 0u64);
+    sc_setup.undelegate_test(&delegator, exp(4, 18));
+    sc_setup.undelegate_all_test(&caller);
+    sc_setup.check_provider_staked(managed_address!(&provider1), exp(8, 18));
+    sc_setup.check_provider_staked(managed_address!(&provider2), exp(6, 18));
+    sc_setup.check_egld_to_undelegate(big_zero.clone());
+
+    // withdraw from both providers
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_all_test(&caller);
+    sc_setup.check_total_withdrawn_egld(exp(6, 18));
+    sc_setup.withdraw_all_test(&caller);
+    sc_setup.check_total_withdrawn_egld(exp(10, 18));
+    sc_setup.withdraw_test(&delegator);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &exp(16, 18));
+    sc_setup.check_total_egld_staked(exp(14, 18));
+}
+
+#[test]
+fn provider_migration_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let big_zero = rust_biguint!(0);
+    let delegator = sc_setup.setup_new_user(20u64);
+    let caller = sc_setup.setup_new_user(1u64);
+    let owner = sc_setup.owner_address.clone();
+    let old_provider = sc_setup.provider_address.clone();
+    let new_provider = SalsaContractSetup::<fn() -> salsa::ContractObj<DebugApi>>::deploy_delegation_provider(
+        &mut sc_setup.blockchain_wrapper,
+        &owner,
+    );
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // delegate, then migrate everything to the new provider
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.start_provider_migration_test(&old_provider, &new_provider);
+    sc_setup.check_provider_staked(managed_address!(&old_provider), big_zero.clone());
+    sc_setup.check_total_egld_staked(exp(10, 18));
+
+    // user endpoints keep working during the migration
+    sc_setup.delegate_test(&delegator, exp(5, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.check_provider_staked(managed_address!(&new_provider), exp(5, 18));
+    sc_setup.undelegate_test(&delegator, exp(3, 18));
+    sc_setup.check_total_egld_staked(exp(12, 18));
+
+    // the withdrawn EGLD covers the pending undelegation, the rest goes to the new provider
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_all_test(&caller);
+    sc_setup.check_egld_to_undelegate(big_zero.clone());
+    sc_setup.check_total_withdrawn_egld(exp(3, 18));
+    sc_setup.check_egld_to_migrate(exp(7, 18));
+
+    // the migration can finish while the protocol is paused
+    sc_setup.set_state_exit_only_test();
+    sc_setup.delegate_migrated_egld_test(&caller);
+    sc_setup.check_egld_to_migrate(big_zero);
+    sc_setup.check_provider_staked(managed_address!(&new_provider), exp(12, 18));
+    sc_setup.check_provider_registered(managed_address!(&old_provider), false);
+    sc_setup.check_total_egld_staked(exp(12, 18));
+
+    // the user can withdraw
+    sc_setup.withdraw_test(&delegator);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &exp(8, 18));
+}
+
+#[test]
+fn legacy_provider_import_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(20u64);
+    let caller = sc_setup.setup_new_user(1u64);
+    let provider = sc_setup.provider_address.clone();
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // stake and undelegate, leaving one undelegation not yet sent to the provider
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.undelegate_test(&delegator, exp(2, 18));
+    sc_setup.undelegate_all_test(&caller);
+    sc_setup.undelegate_test(&delegator, exp(1, 18));
+
+    // upgrade from the single provider version
+    sc_setup.simulate_legacy_provider_test(&provider);
+    sc_setup.set_state_active_fail_test("Legacy provider not imported");
+    sc_setup.import_legacy_provider_test(1);
+    sc_setup.import_legacy_provider_fail_test(1, "No legacy provider");
+    sc_setup.check_provider_staked(managed_address!(&provider), exp(8, 18));
+    sc_setup.check_provider_undelegations_amount(managed_address!(&provider), exp(2, 18));
+    sc_setup.set_state_active_test();
+
+    // the pending undelegation is sent to the imported provider
+    sc_setup.undelegate_all_test(&caller);
+    sc_setup.check_provider_staked(managed_address!(&provider), exp(7, 18));
+    sc_setup.check_provider_undelegations_amount(managed_address!(&provider), exp(3, 18));
+
+    // the user can withdraw
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_all_test(&caller);
+    sc_setup.compute_withdrawn_test(&caller);
+    sc_setup.withdraw_test(&delegator);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &exp(13, 18));
+}
+
+#[test]
+fn netting_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let big_zero = rust_biguint!(0);
+    let delegator1 = sc_setup.setup_new_user(10u64);
+    let delegator2 = sc_setup.setup_new_user(3u64);
+    let delegator3 = sc_setup.setup_new_user(5u64);
+    let caller = sc_setup.setup_new_user(1u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // delegate, then undelegate
+    sc_setup.delegate_test(&delegator1, exp(10, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.undelegate_test(&delegator1, exp(4, 18));
+    sc_setup.check_egld_to_undelegate(exp(4, 18));
+
+    // new delegations cover the pending undelegation
+    sc_setup.delegate_test(&delegator2, exp(3, 18));
+    sc_setup.check_egld_to_delegate(big_zero.clone());
+    sc_setup.check_egld_to_undelegate(exp(1, 18));
+    sc_setup.check_total_withdrawn_egld(exp(3, 18));
+    sc_setup.blockchain_wrapper.set_block_epoch(2u64);
+    sc_setup.delegate_test(&delegator3, exp(5, 18));
+    sc_setup.check_egld_to_delegate(exp(4, 18));
+    sc_setup.check_egld_to_undelegate(big_zero.clone());
+
+    // an undelegation covered by pending delegations can be withdrawn right away
+    sc_setup.undelegate_test(&delegator1, exp(2, 18));
+    sc_setup.check_egld_to_delegate(exp(2, 18));
+    sc_setup.check_total_withdrawn_egld(exp(6, 18));
+    sc_setup.withdraw_test(&delegator1);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator1, &exp(2, 18));
+
+    // the rest is withdrawn after the unbond period, without calling the provider
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_test(&delegator1);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator1, &exp(6, 18));
+    sc_setup.check_total_users_undelegations_amount(big_zero.clone());
+    sc_setup.check_provider_staked(managed_address!(&sc_setup.provider_address.clone()), exp(10, 18));
+    sc_setup.check_total_egld_staked(exp(12, 18));
+}
+
+#[test]
+fn undelegation_token_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let legacy_delegator = sc_setup.setup_new_user(10u64);
+    let delegator = sc_setup.setup_new_user(1u64);
+    let caller = sc_setup.setup_new_user(1u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // undelegate the old way, then migrate to an undelegation token
+    sc_setup.delegate_test(&legacy_delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.legacy_undelegate_test(&legacy_delegator, exp(3, 18));
+    sc_setup.check_legacy_user_undelegations_length(managed_address!(&legacy_delegator), 1);
+    sc_setup.migrate_undelegations_test(&legacy_delegator);
+    sc_setup.check_legacy_user_undelegations_length(managed_address!(&legacy_delegator), 0);
+    sc_setup.check_user_undelegations_length(&legacy_delegator, 1);
+    sc_setup.check_user_undelegations_amount(&legacy_delegator, exp(3, 18));
+    sc_setup.check_total_users_undelegations_amount(exp(3, 18));
+
+    // a new delegation covers part of the undelegation, the rest goes to the provider
+    sc_setup.delegate_test(&delegator, exp(1, 18));
+    sc_setup.undelegate_all_test(&caller);
+
+    // partial withdraw returns a token for the remaining amount
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_test(&legacy_delegator);
+    sc_setup.blockchain_wrapper.check_egld_balance(&legacy_delegator, &exp(1, 18));
+    sc_setup.check_user_undelegations_length(&legacy_delegator, 1);
+    sc_setup.check_user_undelegations_amount(&legacy_delegator, exp(2, 18));
+
+    // withdraw the rest
+    sc_setup.withdraw_all_test(&caller);
+    sc_setup.compute_withdrawn_test(&caller);
+    sc_setup.withdraw_test(&legacy_delegator);
+    sc_setup.blockchain_wrapper.check_egld_balance(&legacy_delegator, &exp(3, 18));
+    sc_setup.check_user_undelegations_length(&legacy_delegator, 0);
+    sc_setup.check_total_users_undelegations_amount(rust_biguint!(0));
+}
+
+#[test]
+fn reserve_token_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let big_zero = rust_biguint!(0);
+    let reserver = sc_setup.setup_new_user(2u64);
+    let legacy_reserver = sc_setup.setup_new_user(3u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // add reserve, the points are received as tokens
+    sc_setup.add_reserve_test(&reserver, exp(2, 18));
+    sc_setup.check_user_reserve_points(&reserver, exp(2, 18));
+    sc_setup.check_user_reserve(&reserver, exp(2, 18));
+
+    // migrate the points of a reserve added the old way
+    sc_setup.legacy_add_reserve_test(&legacy_reserver, exp(3, 18));
+    sc_setup.check_legacy_user_reserve_points(managed_address!(&legacy_reserver), exp(3, 18));
+    sc_setup.migrate_reserve_test(&legacy_reserver);
+    sc_setup.check_legacy_user_reserve_points(managed_address!(&legacy_reserver), big_zero.clone());
+    sc_setup.check_user_reserve_points(&legacy_reserver, exp(3, 18));
+    sc_setup.check_egld_reserve(exp(5, 18));
+
+    // partial remove returns the unused tokens
+    sc_setup.blockchain_wrapper.set_block_epoch(2u64);
+    sc_setup.remove_reserve_test(&legacy_reserver, exp(1, 18));
+    sc_setup.blockchain_wrapper.check_egld_balance(&legacy_reserver, &exp(1, 18));
+    sc_setup.check_user_reserve(&legacy_reserver, exp(2, 18) - 1u64);
+
+    // remove everything
+    sc_setup.remove_reserve_test(&reserver, exp(2, 18));
+    sc_setup.remove_reserve_test(&legacy_reserver, exp(2, 18) - 1u64);
+    sc_setup.check_user_reserve_points(&reserver, big_zero.clone());
+    sc_setup.check_user_reserve_points(&legacy_reserver, big_zero.clone());
+    sc_setup.check_egld_reserve(big_zero);
+}
+
+#[test]
+fn undelegate_now_fee_curve_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let reserver = sc_setup.setup_new_user(10u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // delegate and add reserve
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+    sc_setup.add_reserve_test(&reserver, exp(10, 18));
+
+    // flat fee until the max fee is set
+    sc_setup.check_undelegate_now_fee_quote(exp(1, 18), 200);
+    sc_setup.set_undelegate_now_max_fee_test(1000);
+
+    // full reserve: 2% + 8% * 0.5 / 10
+    sc_setup.check_undelegate_now_fee_quote(exp(1, 18), 240);
+    sc_setup.undelegate_now_test(&delegator, exp(1, 18), exp(976, 15));
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &exp(976, 15));
+    sc_setup.check_available_egld_reserve(exp(9024, 15));
+
+    // the fee rises as the reserve is used: 2% + 8% * 1.5 / 10.024, once the fee is vested
+    sc_setup.blockchain_wrapper.set_block_epoch(2u64);
+    sc_setup.check_undelegate_now_fee_quote(exp(1, 18), 319);
+
+    // an amount bigger than the reserve is capped at full utilization
+    sc_setup.check_undelegate_now_fee_quote(exp(20, 18), 639);
+}
+
+#[test]
+fn protocol_fee_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let treasury = sc_setup.setup_new_user(0u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+
+    // no fee without a treasury
+    sc_setup.add_rewards_test(&delegator, exp(1, 18));
+    sc_setup.check_total_egld_staked(exp(11, 18));
+    sc_setup.check_liquid_supply(exp(10, 18));
+
+    // 10% of the rewards are minted to the treasury at the price after the rewards
+    sc_setup.set_protocol_fee_test(1000, &treasury);
+    sc_setup.add_rewards_test(&delegator, exp(11, 17));
+    sc_setup.check_total_egld_staked(exp(121, 17));
+    sc_setup.check_total_protocol_fees(exp(11, 16));
+    let fee_tokens = exp(11, 16) * (exp(10, 18) + 1_000u64) / (exp(1199, 16) + 1_000u64);
+    sc_setup.blockchain_wrapper.check_esdt_balance(&treasury, TOKEN_ID, &fee_tokens);
+    sc_setup.check_liquid_supply(exp(10, 18) + fee_tokens);
+}
+
+#[test]
+fn compound_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let caller = sc_setup.setup_new_user(1u64);
+    let provider = sc_setup.provider_address.clone();
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&caller);
+
+    // one year of 10% rewards, claimed and credited in a single transaction
+    sc_setup.blockchain_wrapper.set_block_epoch(365u64);
+    sc_setup.compound_test(&caller);
+    sc_setup.check_total_egld_staked(exp(11, 18));
+    sc_setup.check_egld_to_delegate(exp(1, 18));
+    sc_setup.compound_fail_test(&caller, "Nothing to compound");
+
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.check_egld_to_delegate(rust_biguint!(0));
+    sc_setup.check_provider_staked(managed_address!(&provider), exp(11, 18));
+}
+
+#[test]
+fn keeper_rewards_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(11u64);
+    let keeper = sc_setup.setup_new_user(1u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // 10% of the compounded rewards fund rewards of 0.01, capped at 0.015 per epoch
+    sc_setup.set_keeper_rewards_test(exp(1, 16), exp(15, 15), 1000);
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&keeper);
+
+    sc_setup.blockchain_wrapper.set_block_epoch(365u64);
+    sc_setup.compound_test(&keeper);
+    sc_setup.check_total_egld_staked(exp(109, 17));
+    sc_setup.check_egld_to_delegate(exp(9, 17));
+    sc_setup.check_keeper_claimable_rewards(&keeper, exp(1, 16));
+
+    // the epoch cap limits the next reward
+    sc_setup.delegate_test(&delegator, exp(1, 18));
+    sc_setup.delegate_all_test(&keeper);
+    sc_setup.undelegate_test(&delegator, exp(1, 18));
+    sc_setup.undelegate_all_test(&keeper);
+    sc_setup.check_keeper_claimable_rewards(&keeper, exp(15, 15));
+
+    // a computeWithdrawn with nothing to compute isn't rewarded
+    sc_setup.blockchain_wrapper.set_block_epoch(366u64);
+    sc_setup.compute_withdrawn_test(&keeper);
+    sc_setup.check_keeper_claimable_rewards(&keeper, exp(15, 15));
+
+    // the computeWithdrawn done by a user withdraw isn't rewarded
+    sc_setup.blockchain_wrapper.set_block_epoch(375u64);
+    sc_setup.withdraw_all_test(&keeper);
+    sc_setup.withdraw_test(&delegator);
+    sc_setup.check_keeper_claimable_rewards(&delegator, rust_biguint!(0));
+    sc_setup.compute_withdrawn_test(&keeper);
+    sc_setup.check_keeper_claimable_rewards(&keeper, exp(25, 15));
+    sc_setup.check_keeper_rewards_pool(exp(75, 15));
+
+    // only the first unDelegateAll of the epoch is rewarded
+    sc_setup.blockchain_wrapper.set_block_epoch(376u64);
+    sc_setup.undelegate_test(&delegator, exp(1, 18));
+    sc_setup.undelegate_all_test(&keeper);
+    sc_setup.undelegate_test(&delegator, exp(1, 18));
+    sc_setup.undelegate_all_test(&keeper);
+    sc_setup.check_keeper_claimable_rewards(&keeper, exp(35, 15));
+
+    // the rewards are claimed separately
+    sc_setup.claim_keeper_rewards_test(&keeper);
+    sc_setup.blockchain_wrapper.check_egld_balance(&keeper, &(exp(1, 18) + exp(35, 15)));
+    sc_setup.check_keeper_claimable_rewards(&keeper, rust_biguint!(0));
+}
+
+#[test]
+fn pending_operations_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let keeper = sc_setup.setup_new_user(1u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.check_pending_operations([true, false, true, false, false]);
+    sc_setup.delegate_all_test(&keeper);
+    sc_setup.check_pending_operations([false, false, true, false, false]);
+
+    sc_setup.undelegate_test(&delegator, exp(2, 18));
+    sc_setup.check_pending_operations([false, true, true, false, false]);
+    sc_setup.undelegate_all_test(&keeper);
+
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.check_pending_operations([false, false, true, true, false]);
+    sc_setup.withdraw_all_test(&keeper);
+    sc_setup.check_pending_operations([false, false, true, false, true]);
+    sc_setup.compute_withdrawn_test(&keeper);
+    sc_setup.check_pending_operations([false, false, true, false, false]);
+}
+
+#[test]
+fn registries_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let reserver1 = sc_setup.setup_new_user(1u64);
+    let reserver2 = sc_setup.setup_new_user(2u64);
+    let caller = sc_setup.setup_new_user(1u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // legacy positions are listed once registered by the owner
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.legacy_add_reserve_test(&reserver1, exp(1, 18));
+    sc_setup.legacy_add_reserve_test(&reserver2, exp(2, 18));
+    sc_setup.legacy_undelegate_test(&delegator, exp(1, 18));
+    sc_setup.register_legacy_users_test(&[&reserver1, &reserver2, &delegator, &caller]);
+    sc_setup.check_registries_lengths([0, 2, 1, 0, 0]);
+    sc_setup.check_legacy_reserve_users_page(0, 5, &[(&reserver1, exp(1, 18)), (&reserver2, exp(2, 18))]);
+    sc_setup.check_legacy_reserve_users_page(1, 1, &[(&reserver2, exp(2, 18))]);
+    sc_setup.check_legacy_reserve_users_page(2, 1, &[]);
+
+    // migrations move the positions to tokens
+    sc_setup.migrate_reserve_test(&reserver1);
+    sc_setup.migrate_undelegations_test(&delegator);
+    sc_setup.check_registries_lengths([1, 1, 0, 1, 1]);
+    sc_setup.check_legacy_reserve_users_page(0, 5, &[(&reserver2, exp(2, 18))]);
+    sc_setup.check_reserve_providers_page(0, 5, &[(&reserver1, exp(1, 18))]);
+    sc_setup.check_undelegation_users_page(0, 5, &[(&delegator, exp(1, 18))]);
+
+    // new positions are listed under the address that received them
+    sc_setup.add_reserve_test(&caller, exp(1, 18));
+    sc_setup.undelegate_test(&delegator, exp(1, 18));
+    sc_setup.check_registries_lengths([2, 1, 0, 2, 1]);
+    sc_setup.check_reserve_providers_page(1, 1, &[(&caller, exp(1, 18))]);
+    sc_setup.check_undelegation_users_page(0, 5, &[(&delegator, exp(2, 18))]);
+
+    // and removed once removed or withdrawn
+    sc_setup.remove_reserve_test(&reserver1, exp(1, 18));
+    sc_setup.check_reserve_providers_page(0, 5, &[(&caller, exp(1, 18))]);
+    sc_setup.undelegate_all_test(&caller);
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_all_test(&caller);
+    sc_setup.withdraw_test(&delegator);
+    sc_setup.check_registries_lengths([0, 1, 0, 1, 0]);
+}
+
+#[test]
+fn info_views_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let reserver = sc_setup.setup_new_user(3u64);
+    let caller = sc_setup.setup_new_user(1u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.add_reserve_test(&reserver, exp(2, 18));
+    sc_setup.legacy_add_reserve_test(&reserver, exp(1, 18));
+    sc_setup.check_contract_info(State::Active, exp(10, 18), exp(3, 18), 1);
+    sc_setup.check_user_position(&reserver, exp(3, 18), 0, 0, rust_biguint!(0));
+
+    sc_setup.undelegate_test(&delegator, exp(1, 18));
+    sc_setup.legacy_undelegate_test(&delegator, exp(1, 18));
+    sc_setup.check_user_position(&delegator, rust_biguint!(0), 0, 2, rust_biguint!(0));
+
+    // matured undelegations are withdrawable once withdrawn from the provider
+    sc_setup.undelegate_all_test(&caller);
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.check_user_position(&delegator, rust_biguint!(0), 2, 0, rust_biguint!(0));
+    sc_setup.withdraw_all_test(&caller);
+    sc_setup.check_user_position(&delegator, rust_biguint!(0), 2, 0, exp(2, 18));
+    sc_setup.check_contract_info(State::Active, exp(8, 18), exp(3, 18), 1);
+
+    // losses cut the token and the legacy undelegations alike
+    sc_setup.add_undelegations_loss_test(11, exp(5, 17));
+    sc_setup.check_user_position(&delegator, rust_biguint!(0), 2, 0, exp(1, 18));
+}
+
+#[test]
+fn rate_history_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let reserver = sc_setup.setup_new_user(5u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+    sc_setup.add_reserve_test(&reserver, exp(5, 18));
+
+    // 1% in 7 epochs for holders, a 2% fee on 1.01 EGLD for the 5 EGLD reserve
+    sc_setup.blockchain_wrapper.set_block_epoch(8u64);
+    sc_setup.add_rewards_test(&delegator, exp(1, 17));
+    sc_setup.undelegate_now_test(&delegator, exp(1, 18), rust_biguint!(0));
+
+    // the fee is vested after one epoch
+    sc_setup.blockchain_wrapper.set_block_epoch(9u64);
+    sc_setup.check_apr(8, 4562, 1843);
+
+    // not enough history
+    sc_setup.check_apr(30, 0, 0);
+
+    // the closest older snapshot is used
+    sc_setup.blockchain_wrapper.set_block_epoch(12u64);
+    sc_setup.check_apr(3, 9124, 3686);
+    sc_setup.check_apr(10, 3318, 1340);
+}
+
+#[test]
+fn twap_rate_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+    sc_setup.blockchain_wrapper.set_block_timestamp(1_000u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+
+    // the price moves to 1.1 at the start of epoch 2
+    sc_setup.blockchain_wrapper.set_block_epoch(2u64);
+    sc_setup.blockchain_wrapper.set_block_timestamp(2_000u64);
+    sc_setup.add_rewards_test(&delegator, exp(1, 18));
+
+    sc_setup.blockchain_wrapper.set_block_epoch(3u64);
+    sc_setup.blockchain_wrapper.set_block_timestamp(3_000u64);
+    // the price after the rewards is 10 wei below 1.1, because of the virtual shares
+    sc_setup.check_twap_rate(2, exp(105, 16) - 5u64);
+    sc_setup.check_twap_rate(1, exp(11, 17) - 10u64);
+
+    // moves within the cap are allowed, the epoch total is checked against the epoch start
+    sc_setup.set_max_price_change_test(500);
+    sc_setup.add_rewards_test(&delegator, exp(3, 17));
+    sc_setup.check_state(State::Active);
+    sc_setup.add_rewards_test(&delegator, exp(3, 17));
+    sc_setup.check_state(State::ExitOnly);
+}
+
+#[test]
+fn inflation_attack_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let attacker = sc_setup.setup_new_user(1u64);
+    let victim = sc_setup.setup_new_user(2u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // the attacker deposits the minimum, then inflates the price with a large donation
+    sc_setup.delegate_test(&attacker, exp(1, 18));
+    sc_setup.add_rewards_test(&attacker, exp(100, 18));
+
+    // the victim still gets shares, and can't redeem more than deposited
+    let deposit = exp(1, 18) + 7u64;
+    sc_setup.delegate_test(&victim, deposit.clone());
+    let victim_shares = sc_setup.blockchain_wrapper.get_esdt_balance(&victim, TOKEN_ID, 0);
+    assert!(victim_shares > rust_biguint!(0));
+    sc_setup.undelegate_test(&victim, victim_shares);
+    let redeemed = sc_setup.get_user_undelegations_amount(&victim);
+    assert!(redeemed <= deposit);
+    assert!(redeemed + exp(1, 17) > deposit);
+
+    // the attacker can't redeem more than the deposit and the donation
+    sc_setup.undelegate_test(&attacker, exp(1, 18));
+    let redeemed = sc_setup.get_user_undelegations_amount(&attacker);
+    assert!(redeemed <= exp(101, 18));
+}
+
+#[test]
+fn orphaned_stake_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(2u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // stake without holders isn't captured by the next depositor
+    sc_setup.add_rewards_test(&delegator, exp(5, 18));
+    sc_setup.delegate_test(&delegator, exp(2, 18));
+    let shares = sc_setup.blockchain_wrapper.get_esdt_balance(&delegator, TOKEN_ID, 0);
+    sc_setup.undelegate_test(&delegator, shares);
+    let redeemed = sc_setup.get_user_undelegations_amount(&delegator);
+    assert!(redeemed <= exp(2, 18));
+    assert!(redeemed > exp(199, 16));
+}
+
+#[test]
+fn rounding_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(20u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    // round trips at an odd price never return more than deposited
+    sc_setup.delegate_test(&delegator, exp(3, 18));
+    sc_setup.add_rewards_test(&delegator, exp(1, 18) + 1u64);
+    for deposit in [exp(1, 18) + 1u64, exp(1, 18) + 3u64, exp(7, 18) + 11u64] {
+        let shares_before = sc_setup.blockchain_wrapper.get_esdt_balance(&delegator, TOKEN_ID, 0);
+        sc_setup.delegate_test(&delegator, deposit.clone());
+        let shares = sc_setup.blockchain_wrapper.get_esdt_balance(&delegator, TOKEN_ID, 0) - shares_before;
+        let undelegated_before = sc_setup.get_user_undelegations_amount(&delegator);
+        sc_setup.undelegate_test(&delegator, shares);
+        let undelegated = sc_setup.get_user_undelegations_amount(&delegator);
+        assert!(undelegated - undelegated_before <= deposit);
+    }
+}
+
+#[test]
+fn undelegate_now_partial_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let reserver = sc_setup.setup_new_user(2u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+    sc_setup.add_reserve_test(&reserver, exp(2, 18));
+
+    // 5 EGLD can't be covered by the 2 EGLD reserve
+    sc_setup.undelegate_now_fail_test(&delegator, exp(5, 18), rust_biguint!(0), "Not enough funds");
+
+    // the reserve pays what it can, the rest is queued as a normal undelegation
+    sc_setup.undelegate_now_partial_test(&delegator, exp(5, 18), exp(19, 17));
+    let egld_now = exp(2, 18) * 10_000u64 / 9_800u64;
+    let egld_paid = &egld_now - &egld_now * 200u64 / 10_000u64;
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &egld_paid);
+    sc_setup.check_available_egld_reserve(exp(2, 18) - &egld_paid);
+    sc_setup.check_egld_reserve(exp(2, 18));
+    sc_setup.check_unvested_reserve_fees(&egld_now - &egld_paid);
+    sc_setup.check_user_undelegations_amount(&delegator, exp(5, 18) - &egld_now);
+    sc_setup.check_egld_to_undelegate(exp(5, 18));
+
+    // an empty reserve queues everything
+    sc_setup.undelegate_now_partial_test(&delegator, exp(1, 18), rust_biguint!(0));
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &egld_paid);
+    sc_setup.check_user_undelegations_amount(&delegator, exp(6, 18) - &egld_now);
+}
+
+#[test]
+fn undelegation_orders_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let reserver = sc_setup.setup_new_user(5u64);
+    let keeper = sc_setup.setup_new_user(1u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+    sc_setup.add_reserve_test(&reserver, exp(1, 18));
+    sc_setup.set_keeper_rewards_test(exp(1, 16), exp(1, 17), 0);
+    sc_setup.fund_keeper_rewards_pool_test(exp(1, 17));
+
+    // orders must be worth at least the undelegate now minimum
+    sc_setup.place_undelegation_order_fail_test(&delegator, exp(5, 17), 300, "Insufficient undelegated amount");
+
+    // the tokens are escrowed until the reserve can fill the order
+    let order_id = sc_setup.place_undelegation_order_test(&delegator, exp(3, 18), 300);
+    sc_setup.blockchain_wrapper.check_esdt_balance(&delegator, TOKEN_ID, &exp(7, 18));
+    sc_setup.fill_undelegation_order_fail_test(&keeper, order_id, "Not enough funds");
+    sc_setup.add_reserve_test(&reserver, exp(4, 18));
+    sc_setup.fill_undelegation_order_test(&keeper, order_id);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &exp(294, 16));
+    // fills only move funds between users, so they aren't rewarded
+    sc_setup.check_keeper_claimable_rewards(&keeper, rust_biguint!(0));
+    sc_setup.fill_undelegation_order_fail_test(&keeper, order_id, "Order not found");
+
+    // orders above the max fee wait, and only the owner can cancel them
+    let order_id = sc_setup.place_undelegation_order_test(&delegator, exp(1, 18), 100);
+    sc_setup.fill_undelegation_order_fail_test(&keeper, order_id, "Fee above the order max fee");
+    sc_setup.cancel_undelegation_order_fail_test(&keeper, order_id, "Not the order owner");
+    sc_setup.cancel_undelegation_order_test(&delegator, order_id);
+    sc_setup.blockchain_wrapper.check_esdt_balance(&delegator, TOKEN_ID, &exp(7, 18));
+
+    // owners can fill their own orders
+    let order_id = sc_setup.place_undelegation_order_test(&delegator, exp(1, 18), 300);
+    sc_setup.fill_undelegation_order_test(&delegator, order_id);
+    sc_setup.blockchain_wrapper.check_esdt_balance(&delegator, TOKEN_ID, &exp(6, 18));
+}
+
+#[test]
+fn reserve_fees_vesting_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let reserver = sc_setup.setup_new_user(5u64);
+    let jit_reserver = sc_setup.setup_new_user(5u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+    sc_setup.set_reserve_fees_vesting_epochs_fail_test(0, "Vesting epochs must be between 1 and 30");
+    sc_setup.set_reserve_fees_vesting_epochs_test(10);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+    sc_setup.add_reserve_test(&reserver, exp(5, 18));
+
+    // a provider joining right before a large exit only gets the fees vested while it stays
+    sc_setup.add_reserve_test(&jit_reserver, exp(5, 18));
+    sc_setup.undelegate_now_test(&delegator, exp(4, 18), exp(3, 18));
+    let fees = exp(4, 18) - sc_setup.blockchain_wrapper.get_egld_balance(&delegator);
+    sc_setup.check_unvested_reserve_fees(fees.clone());
+    sc_setup.check_egld_reserve(exp(10, 18));
+    sc_setup.blockchain_wrapper.set_block_epoch(2u64);
+    let jit_fees = &fees / 20u64;
+    sc_setup.check_user_reserve(&jit_reserver, exp(5, 18) + &jit_fees);
+    sc_setup.remove_reserve_test(&jit_reserver, exp(5, 18) + &jit_fees);
+    sc_setup.blockchain_wrapper.check_egld_balance(&jit_reserver, &(exp(5, 18) + &jit_fees));
+
+    // later fees vest on their own schedule, without delaying the earlier ones
+    sc_setup.blockchain_wrapper.set_block_epoch(6u64);
+    let egld_balance = sc_setup.blockchain_wrapper.get_egld_balance(&delegator);
+    sc_setup.undelegate_now_test(&delegator, exp(1, 18), exp(9, 17));
+    let new_fees = exp(1, 18) + &egld_balance - sc_setup.blockchain_wrapper.get_egld_balance(&delegator);
+    sc_setup.check_reserve_fees_batches(vec![(&fees / 2u64, 11), (new_fees.clone(), 16)]);
+
+    // the rest vests linearly to the long term provider
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.check_user_reserve(&reserver, exp(5, 18) + &fees - &jit_fees + &new_fees / 2u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(16u64);
+    sc_setup.check_user_reserve(&reserver, exp(5, 18) + &fees - &jit_fees + &new_fees);
+}
+
+#[test]
+fn reserve_lock_tiers_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let reserver = sc_setup.setup_new_user(5u64);
+    let locked_reserver = sc_setup.setup_new_user(6u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+    sc_setup.set_reserve_lock_tier_test(30, 20_000);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+    sc_setup.add_reserve_test(&reserver, exp(5, 18));
+    sc_setup.add_locked_reserve_fail_test(&locked_reserver, exp(1, 18), 7, "Lock tier not found");
+    sc_setup.add_locked_reserve_test(&locked_reserver, exp(5, 18), 30);
+    sc_setup.blockchain_wrapper.check_esdt_balance(&locked_reserver, RESERVE_TOKEN_ID, &exp(0, 0));
+    sc_setup.check_user_locked_and_queued_reserve(&locked_reserver, exp(5, 18), rust_biguint!(0));
+    sc_setup.check_user_locked_and_queued_reserve(&reserver, rust_biguint!(0), rust_biguint!(0));
+
+    // the locked points count double for the fees
+    sc_setup.undelegate_now_test(&delegator, exp(4, 18), exp(3, 18));
+    let fees = exp(4, 18) - sc_setup.blockchain_wrapper.get_egld_balance(&delegator);
+    let boost_fees = &fees / 3u64;
+    sc_setup.blockchain_wrapper.set_block_epoch(2u64);
+    sc_setup.check_user_reserve(&reserver, exp(5, 18) + (&fees - &boost_fees) / 2u64);
+
+    // the lock is enforced
+    sc_setup.blockchain_wrapper.set_block_epoch(30u64);
+    sc_setup.unlock_reserve_fail_test(&locked_reserver, "Reserve still locked");
+    sc_setup.unlock_reserve_fail_test(&reserver, "No locked reserve");
+    sc_setup.blockchain_wrapper.set_block_epoch(31u64);
+    sc_setup.unlock_reserve_test(&locked_reserver);
+    sc_setup.check_user_locked_and_queued_reserve(&locked_reserver, rust_biguint!(0), rust_biguint!(0));
+    // the boost rewards are rounded down
+    let remaining_reserve = sc_setup.get_user_reserve(&locked_reserver);
+    assert!(remaining_reserve <= exp(5, 18) + (&fees - &boost_fees) / 2u64 + &boost_fees);
+    assert!(remaining_reserve >= exp(5, 18) + (&fees - &boost_fees) / 2u64 + &boost_fees - 10u64);
+    sc_setup.remove_reserve_test(&locked_reserver, remaining_reserve.clone());
+    sc_setup.blockchain_wrapper.check_egld_balance(&locked_reserver, &(exp(1, 18) + remaining_reserve));
+}
+
+#[test]
+fn exit_only_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(2u64);
+    let reserver = sc_setup.setup_new_user(2u64);
+    let caller = sc_setup.setup_new_user(1u64);
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(1, 18));
+    sc_setup.delegate_all_test(&caller);
+    sc_setup.add_reserve_test(&reserver, exp(2, 18));
+
+    // deposits are blocked
+    sc_setup.set_state_exit_only_test();
+    sc_setup.delegate_fail_test(&delegator, exp(1, 18), "Not active");
+
+    // exits keep working
+    sc_setup.undelegate_test(&delegator, exp(1, 18));
+    sc_setup.undelegate_all_test(&caller);
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_all_test(&caller);
+    sc_setup.compute_withdrawn_test(&caller);
+    sc_setup.withdraw_test(&delegator);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &exp(2, 18));
+    sc_setup.remove_reserve_test(&reserver, exp(2, 18));
+    sc_setup.blockchain_wrapper.check_egld_balance(&reserver, &exp(2, 18));
+
+    // deprecated is final
+    sc_setup.set_state_deprecated_test();
+    sc_setup.set_state_active_fail_test("Deprecated state");
+}
+
+#[test]
+fn reconcile_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let provider = sc_setup.provider_address.clone();
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+    sc_setup.set_reconcile_tolerance_test(100);
+
+    // nothing to adjust
+    sc_setup.reconcile_test(&delegator, &provider);
+    sc_setup.check_total_egld_staked(exp(10, 18));
+
+    // a call still in flight blocks the reconcile
+    sc_setup.start_provider_call_test(&provider);
+    sc_setup.reconcile_fail_test(&delegator, &provider, "Provider calls pending");
+    sc_setup.end_provider_call_test(&provider);
+
+    // sent undelegations match the provider's unstaked funds
+    sc_setup.undelegate_test(&delegator, exp(1, 18));
+    sc_setup.undelegate_all_test(&delegator);
+    sc_setup.reconcile_test(&delegator, &provider);
+    sc_setup.check_total_egld_staked(exp(9, 18));
+    sc_setup.check_state(State::Active);
+
+    // within tolerance, the tracked stake follows the provider
+    sc_setup.remove_tracked_stake_test(&provider, exp(5, 16));
+    sc_setup.reconcile_test(&delegator, &provider);
+    sc_setup.check_provider_staked(managed_address!(&provider), exp(9, 18));
+    sc_setup.check_total_egld_staked(exp(905, 16));
+
+    // beyond tolerance, the contract only allows exits and nothing is adjusted
+    sc_setup.remove_tracked_stake_test(&provider, exp(1, 18));
+    sc_setup.reconcile_test(&delegator, &provider);
+    sc_setup.check_provider_staked(managed_address!(&provider), exp(8, 18));
+    sc_setup.check_total_egld_staked(exp(905, 16));
+    sc_setup.check_state(State::ExitOnly);
+
+    // missing undelegations beyond tolerance also pause the contract
+    sc_setup.add_tracked_stake_test(&provider, exp(1, 18));
+    sc_setup.set_state_active_test();
+    sc_setup.add_tracked_undelegation_test(&provider, exp(1, 18));
+    sc_setup.reconcile_test(&delegator, &provider);
+    sc_setup.check_state(State::ExitOnly);
+}
+
+#[test]
+fn stake_loss_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let reserver = sc_setup.setup_new_user(2u64);
+    let provider = sc_setup.provider_address.clone();
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+    sc_setup.add_reserve_test(&reserver, exp(2, 18));
+    sc_setup.undelegate_test(&delegator, exp(2, 18));
+    sc_setup.set_reconcile_tolerance_test(2000);
+    sc_setup.set_reserve_loss_share_test(5000);
+
+    // lose 1: the reserve covers 0.5, the holders (8) take the rest
+    sc_setup.add_tracked_stake_test(&provider, exp(1, 18));
+    sc_setup.reconcile_test(&delegator, &provider);
+    sc_setup.check_total_losses(exp(1, 18));
+    sc_setup.check_uncovered_losses(rust_biguint!(0));
+    sc_setup.check_egld_reserve(exp(15, 17));
+    sc_setup.check_available_egld_reserve(exp(15, 17));
+    sc_setup.check_egld_to_delegate(exp(5, 17));
+    sc_setup.check_total_egld_staked(exp(75, 17));
+    sc_setup.check_egld_to_undelegate(exp(2, 18));
+    sc_setup.check_total_users_undelegations_amount(exp(2, 18));
+    sc_setup.check_provider_staked(managed_address!(&provider), exp(10, 18));
+
+    // the undelegation token pays the full amount
+    sc_setup.undelegate_all_test(&delegator);
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_all_test(&delegator);
+    sc_setup.compute_withdrawn_test(&delegator);
+    sc_setup.withdraw_test(&delegator);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &exp(2, 18));
+    sc_setup.check_user_undelegations_length(&delegator, 0);
+}
+
+#[test]
+fn undelegation_loss_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator = sc_setup.setup_new_user(10u64);
+    let provider = sc_setup.provider_address.clone();
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator, exp(10, 18));
+    sc_setup.delegate_all_test(&delegator);
+    sc_setup.undelegate_test(&delegator, exp(2, 18));
+
+    // the provider returns 1.5 of the 2 undelegated
+    sc_setup.slash_pending_undelegations_test(&provider, exp(5, 17));
+    sc_setup.undelegate_all_test(&delegator);
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_all_test(&delegator);
+
+    // an undelegation still unbonding is not exposed to the loss
+    sc_setup.undelegate_test(&delegator, exp(1, 18));
+    sc_setup.undelegate_all_test(&delegator);
+
+    // the unpaid part is only lost once the owner confirms it
+    sc_setup.check_total_losses(rust_biguint!(0));
+    sc_setup.check_unpaid_undelegations(&provider, exp(5, 17));
+    sc_setup.confirm_undelegation_loss_test(&provider);
+    sc_setup.confirm_undelegation_loss_fail_test(&provider, "No unpaid undelegations");
+    sc_setup.check_total_losses(exp(5, 17));
+    sc_setup.check_total_users_undelegations_amount(exp(25, 17));
+    sc_setup.check_total_egld_staked(exp(7, 18));
+
+    sc_setup.compute_withdrawn_test(&delegator);
+    sc_setup.withdraw_test(&delegator);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &exp(15, 17));
+    sc_setup.check_total_withdrawn_egld(rust_biguint!(0));
+
+    // the later undelegation is paid in full
+    sc_setup.blockchain_wrapper.set_block_epoch(21u64);
+    sc_setup.withdraw_all_test(&delegator);
+    sc_setup.compute_withdrawn_test(&delegator);
+    sc_setup.withdraw_test(&delegator);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator, &exp(25, 17));
+}
+
+#[test]
+fn withdrawn_undelegation_loss_test() {
+    let _ = DebugApi::dummy();
+
+    let mut sc_setup = SalsaContractSetup::new(salsa::contract_obj);
+    let delegator1 = sc_setup.setup_new_user(5u64);
+    let delegator2 = sc_setup.setup_new_user(5u64);
+    let provider = sc_setup.provider_address.clone();
+    sc_setup.blockchain_wrapper.set_block_epoch(1u64);
+
+    sc_setup.delegate_test(&delegator1, exp(5, 18));
+    sc_setup.delegate_test(&delegator2, exp(5, 18));
+    sc_setup.delegate_all_test(&delegator1);
+    sc_setup.undelegate_test(&delegator1, exp(2, 18));
+    sc_setup.undelegate_all_test(&delegator1);
+
+    // the first undelegation is set aside before the loss
+    sc_setup.blockchain_wrapper.set_block_epoch(11u64);
+    sc_setup.withdraw_all_test(&delegator1);
+    sc_setup.compute_withdrawn_test(&delegator1);
+    sc_setup.check_user_withdrawn_egld(exp(2, 18));
+
+    // the provider returns 1 of the next 2 undelegated
+    sc_setup.undelegate_test(&delegator2, exp(2, 18));
+    sc_setup.slash_pending_undelegations_test(&provider, exp(1, 18));
+    sc_setup.undelegate_all_test(&delegator2);
+
+    // the loss is shared by all the undelegation tokens
+    sc_setup.blockchain_wrapper.set_block_epoch(21u64);
+    sc_setup.withdraw_all_test(&delegator1);
+    sc_setup.confirm_undelegation_loss_test(&provider);
+    sc_setup.check_total_losses(exp(1, 18));
+    sc_setup.compute_withdrawn_test(&delegator1);
+    sc_setup.withdraw_test(&delegator1);
+    sc_setup.withdraw_test(&delegator2);
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator1, &exp(15, 17));
+    sc_setup.blockchain_wrapper.check_egld_balance(&delegator2, &exp(15, 17));
+    sc_setup.check_user_withdrawn_egld(rust_biguint!(0));
+    sc_setup.check_total_withdrawn_egld(rust_biguint!(0));
 }
 
 pub fn exp(value: u64, e: u32) -> num_bigint::BigUint {
@@ -5,9 +5,9 @@
 ////////////////////////////////////////////////////
 
 // Init:                                 1
-// Endpoints:                           36
+// Endpoints:                          134
 // Async Callback:                       1
-// Total number of exported functions:  38
+// Total number of exported functions: 136
 
 #![no_std]
 #![feature(lang_items)]
@@ -21,21 +21,57 @@ multiversx_sc_wasm_adapter::endpoints! {
         delegate
         unDelegate
         withdraw
+        migrateUndelegations
         addReserve
+        unlockReserve
         removeReserve
+        processReserveExits
+        cancelReserveExit
+        claimReserveExit
+        migrateReserve
         unDelegateNow
+        placeUndelegationOrder
+        cancelUndelegationOrder
+        fillUndelegationOrder
+        delegateAll
         unDelegateAll
         compound
         withdrawAll
         computeWithdrawn
+        claimKeeperRewards
+        reconcile
+        confirmUndelegationLoss
+        importLegacyProvider
+        startProviderMigration
+        delegateMigratedEgld
+        getPendingOperations
+        getContractInfo
+        getUserPosition
         registerLiquidToken
         getLiquidTokenId
+        registerUndelegationToken
+        registerReserveToken
+        getReserveTokenId
+        getUndelegationTokenId
         getLiquidTokenSupply
         setStateActive
         setStateInactive
+        setStateExitOnly
+        setStateDeprecated
         getState
-        setProviderAddress
-        getProviderAddress
+        addProvider
+        setProviderWeight
+        removeProvider
+        setReconcileTolerance
+        getReconcileTolerance
+        getProviders
+        getProviderPendingCalls
+        getLegacyProviderAddress
+        getProviderUndelegations
+        getMigrationSource
+        getMigrationTarget
+        getMigrationUndelegations
+        getEgldToMigrate
         getUnbondPeriod
         setUnbondPeriod
         getUserUndelegations
@@ -43,6 +79,7 @@ multiversx_sc_wasm_adapter::endpoints! {
         getUserWithdrawnEgld
         getTotalWithdrawnEgld
         getTotalUserUndelegations
+        getEgldToDelegate
         getEgldReserve
         getReservePoints
         getAvailableEgldReserve
@@ -50,9 +87,70 @@ multiversx_sc_wasm_adapter::endpoints! {
         getUsersReservePoints
         setUndelegateNowFee
         getUndelegateNowFee
+        setUndelegateNowMaxFee
+        getUndelegateNowMaxFee
+        getUndelegateNowFeeQuote
         getReservePointsAmount
         getReserveEgldAmount
         getUserReserve
+        setReserveFeesVestingEpochs
+        getReserveFeesBatches
+        setReserveLockTier
+        removeReserveLockTier
+        getReserveLockTiers
+        getLockedReserve
+        getReserveBoostWeight
+        getReserveBoostPool
+        getReserveFeesVestingEpochs
+        getUnvestedReserveFees
+        getUndelegationOrders
+        getUndelegationOrder
+        getReserveExits
+        getReserveExitsCount
+        getQueuedReservePoints
+        getUserQueuedReservePoints
+        getReserveExitClaimable
+        getReserveProviders
+        getUndelegationUsers
+        getUserUndelegationTokens
+        registerLegacyUsers
+        getUndelegationTokens
+        getLegacyReserveUsers
+        getLegacyUndelegationUsers
+        getUndelegationTokensCount
+        getReserveProvidersCount
+        getUndelegationUsersCount
+        getLegacyReserveUsersCount
+        getLegacyUndelegationUsersCount
+        getLockedReserveUsers
+        getLockedReserveUsersCount
+        setReserveLossShare
+        getReserveLossShare
+        getUnpaidUndelegations
+        getTotalLosses
+        getUncoveredLosses
+        getUndelegationsLosses
+        setProtocolFee
+        getProtocolFee
+        setTreasuryAddress
+        getTreasuryAddress
+        getTotalProtocolFees
+        getTotalProtocolFeeTokens
+        setKeeperRewards
+        getKeeperReward
+        getKeeperMaxEpochRewards
+        getKeeperRewardsShare
+        getKeeperRewardsPool
+        getKeeperEpochRewards
+        getKeeperClaimableRewards
+        setMaxPriceChange
+        getMaxPriceChange
+        getTwapRate
+        getCumulativePrice
+        getRateSnapshot
+        getApr
+        getReserveApr
+        getReservePrice
         getTokenPrice
         callBack
     )